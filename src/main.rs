@@ -1,18 +1,24 @@
 use clap::{App, Arg};
 use opencv::imgproc::resize;
+#[cfg(feature = "tracker")]
+use opencv::tracking;
 use opencv::{
     core::{self, Mat},
     highgui, imgcodecs, imgproc,
+    prelude::*,
+    video, videoio,
 };
 use rand::seq::SliceRandom;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, Error, Read, Write};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread::{self, sleep};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 fn main() {
     let app = App::new("StackOpenCV")
@@ -31,29 +37,32 @@ fn main() {
                 .short('d')
                 .long("debug")
                 .help("Enables debug mode"),
+        )
+        .arg(
+            Arg::new("profile")
+                .short('p')
+                .long("profile")
+                .help("Reports time spent in each command"),
         );
     let matches = app.clone().get_matches();
 
     if let Some(script) = matches.value_of("script") {
-        if matches.is_present("debug") {
-            let mut stack = Executor::new(Mode::Debug);
-            stack.evaluate_program(match get_file_contents(Path::new(&script.to_string())) {
-                Ok(code) => code,
-                Err(err) => {
-                    println!("Error! {err}");
-                    return;
-                }
-            })
+        let mut stack = if matches.is_present("debug") {
+            Executor::new(Mode::Debug)
         } else {
-            let mut stack = Executor::new(Mode::Script);
-            stack.evaluate_program(match get_file_contents(Path::new(&script.to_string())) {
-                Ok(code) => code,
-                Err(err) => {
-                    println!("Error! {err}");
-                    return;
-                }
-            })
+            Executor::new(Mode::Script)
+        };
+        if matches.is_present("profile") {
+            stack.profile = Some(Profile::default());
         }
+        stack.evaluate_program(match get_file_contents(Path::new(&script.to_string())) {
+            Ok(code) => code,
+            Err(err) => {
+                println!("Error! {err}");
+                return;
+            }
+        });
+        stack.show_profile();
     } else {
         // Show a title
         println!("Stack Programming Language: OpenCV Edition");
@@ -91,6 +100,178 @@ fn input(prompt: &str) -> String {
     result.trim().to_string()
 }
 
+/// Read list of [x y] pairs as points
+fn to_points(list: &[Type]) -> Option<Vec<core::Point2f>> {
+    list.iter()
+        .map(|point| match point.get_list().as_slice() {
+            [x, y] => Some(core::Point2f::new(
+                x.get_number() as f32,
+                y.get_number() as f32,
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Make list of [x y] pairs from points
+fn from_points(points: &core::Vector<core::Point2f>) -> Type {
+    Type::List(
+        points
+            .iter()
+            .map(|point| {
+                Type::List(vec![
+                    Type::Number(point.x as f64),
+                    Type::Number(point.y as f64),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Read list of [b g r] values as color
+fn to_color(value: &Type) -> core::Scalar {
+    let channels: Vec<f64> = value.get_list().iter().map(|x| x.get_number()).collect();
+    let channel = |index: usize| channels.get(index).copied().unwrap_or(0.0);
+    core::Scalar::new(channel(0), channel(1), channel(2), 0.0)
+}
+
+/// Convert image to 3-channel BGR
+fn to_bgr(img: &Mat) -> Mat {
+    let mut color_img = Mat::default();
+    match img.channels() {
+        1 => imgproc::cvt_color(img, &mut color_img, imgproc::COLOR_GRAY2BGR, 0).unwrap(),
+        4 => imgproc::cvt_color(img, &mut color_img, imgproc::COLOR_BGRA2BGR, 0).unwrap(),
+        _ => color_img = img.clone(),
+    }
+    color_img
+}
+
+/// Convert image to single channel grayscale
+fn to_gray(img: &Mat) -> Mat {
+    if img.channels() == 1 {
+        return img.clone();
+    }
+    let mut gray_img = Mat::default();
+    imgproc::cvt_color(&to_bgr(img), &mut gray_img, imgproc::COLOR_BGR2GRAY, 0).unwrap();
+    gray_img
+}
+
+/// Map image values through a 256-entry lookup table
+fn apply_lut(img: &Mat, table: Vec<u8>) -> Mat {
+    let lut = Mat::from_slice_2d(&[table]).unwrap();
+    let mut result_img = Mat::default();
+    core::lut(img, &lut, &mut result_img).unwrap();
+    result_img
+}
+
+/// Apply 2x3 affine matrix to image, filling exposed area with black
+fn warp_image(img: &Mat, matrix: &Mat, size: core::Size) -> Mat {
+    let mut warped_img = Mat::default();
+    imgproc::warp_affine(
+        img,
+        &mut warped_img,
+        matrix,
+        size,
+        imgproc::INTER_LINEAR,
+        core::BORDER_CONSTANT,
+        core::Scalar::all(0.0),
+    )
+    .unwrap();
+    warped_img
+}
+
+/// Arrange images into a grid whose cells are the size of the first image
+/// (cells are 8-bit BGR, scaling other depths the same way as imshow)
+fn image_grid(images: &[Mat], columns: usize) -> Mat {
+    let cell = images[0].size().unwrap();
+    let blank = Mat::new_rows_cols_with_default(
+        cell.height,
+        cell.width,
+        core::CV_8UC3,
+        core::Scalar::all(0.0),
+    )
+    .unwrap();
+
+    let mut rows = core::Vector::<Mat>::new();
+    for chunk in images.chunks(columns) {
+        let mut cells = core::Vector::<Mat>::new();
+        for index in 0..columns {
+            match chunk.get(index) {
+                Some(img) => {
+                    let scale = match img.depth() {
+                        core::CV_16U | core::CV_16S => 1.0 / 256.0,
+                        core::CV_32F | core::CV_64F => 255.0,
+                        _ => 1.0,
+                    };
+                    let mut byte_img = Mat::default();
+                    img.convert_to(&mut byte_img, core::CV_8U, scale, 0.0)
+                        .unwrap();
+                    let mut cell_img = Mat::default();
+                    resize(
+                        &to_bgr(&byte_img),
+                        &mut cell_img,
+                        cell,
+                        0.0,
+                        0.0,
+                        imgproc::INTER_LINEAR,
+                    )
+                    .unwrap();
+                    cells.push(cell_img);
+                }
+                // Fill empty trailing cells with black
+                None => cells.push(blank.clone()),
+            }
+        }
+        let mut row_img = Mat::default();
+        core::hconcat(&cells, &mut row_img).unwrap();
+        rows.push(row_img);
+    }
+
+    let mut grid_img = Mat::default();
+    core::vconcat(&rows, &mut grid_img).unwrap();
+    grid_img
+}
+
+/// Write images as animated GIF, showing each frame for the delay in milliseconds
+fn write_gif(path: &str, images: &[Mat], delay: f64) -> Result<(), Box<dyn std::error::Error>> {
+    // Frames are resized to match the first
+    let size = images[0].size()?;
+    let (width, height) = (u16::try_from(size.width)?, u16::try_from(size.height)?);
+
+    let mut encoder = gif::Encoder::new(File::create(path)?, width, height, &[])?;
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+    for img in images {
+        if img.empty() {
+            return Err("frame is not an image".into());
+        }
+        let mut byte_img = Mat::default();
+        img.convert_to(&mut byte_img, core::CV_8U, 1.0, 0.0)?;
+        let mut frame_img = Mat::default();
+        resize(
+            &byte_img,
+            &mut frame_img,
+            size,
+            0.0,
+            0.0,
+            imgproc::INTER_LINEAR,
+        )?;
+        let code = match frame_img.channels() {
+            1 => imgproc::COLOR_GRAY2RGBA,
+            3 => imgproc::COLOR_BGR2RGBA,
+            4 => imgproc::COLOR_BGRA2RGBA,
+            _ => return Err("frame must have 1, 3 or 4 channels".into()),
+        };
+        let mut rgba_img = Mat::default();
+        imgproc::cvt_color(&frame_img, &mut rgba_img, code, 0)?;
+
+        let mut pixels = rgba_img.data_bytes()?.to_vec();
+        let mut frame = gif::Frame::from_rgba_speed(width, height, &mut pixels, 10);
+        frame.delay = (delay / 10.0).round() as u16; // GIF delay is in centiseconds
+        encoder.write_frame(&frame)?;
+    }
+    Ok(())
+}
+
 /// Execution Mode
 #[derive(Clone, Debug)]
 enum Mode {
@@ -107,6 +288,31 @@ enum Type {
     List(Vec<Type>),
     Error(String),
     Image(Mat),
+    Video(Capture),
+    #[cfg(feature = "tracker")]
+    Tracker(Tracking),
+}
+
+/// Video capture shared between copies of the value
+#[derive(Clone)]
+struct Capture(Arc<Mutex<videoio::VideoCapture>>);
+
+impl fmt::Debug for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Capture")
+    }
+}
+
+/// Object tracker shared between copies of the value
+#[cfg(feature = "tracker")]
+#[derive(Clone)]
+struct Tracking(Arc<Mutex<core::Ptr<video::Tracker>>>);
+
+#[cfg(feature = "tracker")]
+impl fmt::Debug for Tracking {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tracking")
+    }
 }
 
 /// Implement methods
@@ -115,7 +321,24 @@ impl Type {
     fn display(&self) -> String {
         match self {
             Type::Number(num) => num.to_string(),
-            Type::String(s) => format!("({})", s),
+            Type::String(s) => {
+                // Escape characters that the tokenizer treats specially,
+                // so that displayed string can be evaluated back as it is
+                let mut escaped = String::new();
+                for c in s.chars() {
+                    match c {
+                        '\n' => escaped.push_str("\\n"),
+                        '\t' => escaped.push_str("\\t"),
+                        '\r' => escaped.push_str("\\r"),
+                        '\\' | '(' | ')' | '[' | ']' | '#' => {
+                            escaped.push('\\');
+                            escaped.push(c);
+                        }
+                        _ => escaped.push(c),
+                    }
+                }
+                format!("({})", escaped)
+            }
             Type::Bool(b) => b.to_string(),
             Type::List(list) => {
                 let result: Vec<String> = list.iter().map(|token| token.display()).collect();
@@ -123,6 +346,9 @@ impl Type {
             }
             Type::Error(err) => format!("error:{err}"),
             Type::Image(_) => "{Image}".to_string(),
+            Type::Video(_) => "{Video}".to_string(),
+            #[cfg(feature = "tracker")]
+            Type::Tracker(_) => "{Tracker}".to_string(),
         }
     }
 
@@ -135,6 +361,9 @@ impl Type {
             Type::List(l) => Type::List(l.to_owned()).display(),
             Type::Error(err) => format!("error:{err}"),
             Type::Image(_) => "{Image}".to_string(),
+            Type::Video(_) => "{Video}".to_string(),
+            #[cfg(feature = "tracker")]
+            Type::Tracker(_) => "{Tracker}".to_string(),
         }
     }
 
@@ -153,6 +382,9 @@ impl Type {
             Type::List(l) => l.len() as f64,
             Type::Error(e) => e.parse().unwrap_or(0f64),
             Type::Image(_) => 1f64,
+            Type::Video(_) => 1f64,
+            #[cfg(feature = "tracker")]
+            Type::Tracker(_) => 1f64,
         }
     }
 
@@ -165,6 +397,9 @@ impl Type {
             Type::List(l) => !l.is_empty(),
             Type::Error(e) => e.parse().unwrap_or(false),
             Type::Image(_) => true,
+            Type::Video(_) => true,
+            #[cfg(feature = "tracker")]
+            Type::Tracker(_) => true,
         }
     }
 
@@ -181,6 +416,9 @@ impl Type {
             Type::List(l) => l.to_vec(),
             Type::Error(e) => vec![Type::Error(e.to_string())],
             Type::Image(_) => vec![],
+            Type::Video(_) => vec![],
+            #[cfg(feature = "tracker")]
+            Type::Tracker(_) => vec![],
         }
     }
 
@@ -190,13 +428,36 @@ impl Type {
             _ => Mat::default(),
         }
     }
+
+    /// Judge does data hold values that can't be identified by their display
+    fn has_opaque(&self) -> bool {
+        match self {
+            Type::Image(_) | Type::Video(_) => true,
+            #[cfg(feature = "tracker")]
+            Type::Tracker(_) => true,
+            Type::List(list) => list.iter().any(|x| x.has_opaque()),
+            _ => false,
+        }
+    }
+}
+
+/// Time spent in each command, when profiling
+#[derive(Clone, Debug, Default)]
+struct Profile {
+    commands: HashMap<String, (Duration, Duration, usize)>, // Self time, total time and calls
+    nested: Duration, // Time of commands run inside the current one
+    is_word: bool,    // Last token was not a command but a string
 }
+
 /// Manage program execution
 #[derive(Clone, Debug)]
 struct Executor {
     stack: Vec<Type>,              // Data stack
     memory: HashMap<String, Type>, // Variable's memory
     mode: Mode,                    // Execution mode
+    cache: HashMap<String, Type>,  // Results of memoized code
+    profile: Option<Profile>,      // Time spent in each command
+    window: String,                // Window to show images in
 }
 
 impl Executor {
@@ -206,6 +467,32 @@ impl Executor {
             stack: Vec::new(),
             memory: HashMap::new(),
             mode,
+            cache: HashMap::new(),
+            profile: None,
+            window: "Image Window".to_string(),
+        }
+    }
+
+    /// Show time spent in each command, when profiling
+    fn show_profile(&self) {
+        if let Some(profile) = &self.profile {
+            // Self time excludes nested commands, so the slowest command comes first
+            let mut report: Vec<_> = profile.commands.iter().collect();
+            report.sort_by_key(|(_, (self_time, _, _))| std::cmp::Reverse(*self_time));
+
+            println!("Profile {{");
+            let max = report.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+            for (name, (self_time, total_time, calls)) in report {
+                println!(
+                    " {:>width$}: {:.6}s self, {:.6}s total ({} calls)",
+                    name,
+                    self_time.as_secs_f64(),
+                    total_time.as_secs_f64(),
+                    calls,
+                    width = max
+                );
+            }
+            println!("}}");
         }
     }
 
@@ -256,7 +543,23 @@ impl Executor {
         let mut hash = false; // Is it Comment
         let mut escape = false; // Flag to indicate next character is escaped
 
-        for c in code.chars() {
+        for (position, c) in code.chars().enumerate() {
+            // Closing delimiter without matching opener
+            let unmatched = match c {
+                ')' => brackets == 0,
+                ']' => brackets == 0 && parentheses == 0,
+                '}' => brackets == 0 && braces == 0,
+                _ => false,
+            };
+            if unmatched && !hash && !escape {
+                self.log_print(format!(
+                    "Error! Unbalanced bracket '{c}' at position {position}\n"
+                ));
+                self.stack
+                    .push(Type::Error("unbalanced-bracket".to_string()));
+                return Vec::new();
+            }
+
             match c {
                 '\\' if !escape => {
                     escape = true;
@@ -303,9 +606,9 @@ impl Executor {
                     if parentheses == 0 && brackets == 0 && !hash {
                         if escape {
                             match c {
-                                'n' => buffer.push_str("\\n"),
-                                't' => buffer.push_str("\\t"),
-                                'r' => buffer.push_str("\\r"),
+                                'n' => buffer.push('\n'),
+                                't' => buffer.push('\t'),
+                                'r' => buffer.push('\r'),
                                 _ => buffer.push(c),
                             }
                         } else {
@@ -322,6 +625,14 @@ impl Executor {
             }
         }
 
+        // Delimiters left open at the end of program
+        if brackets != 0 || parentheses != 0 || braces != 0 {
+            self.log_print("Error! Unbalanced bracket: not closed at the end\n".to_string());
+            self.stack
+                .push(Type::Error("unbalanced-bracket".to_string()));
+            return Vec::new();
+        }
+
         if !buffer.is_empty() {
             syntax.push(buffer);
         }
@@ -390,9 +701,9 @@ impl Executor {
                                 if parentheses == 0 && brackets == 0 && !hash {
                                     if escape {
                                         match c {
-                                            'n' => buffer.push_str("\\n"),
-                                            't' => buffer.push_str("\\t"),
-                                            'r' => buffer.push_str("\\r"),
+                                            'n' => buffer.push('\n'),
+                                            't' => buffer.push('\t'),
+                                            'r' => buffer.push('\r'),
                                             _ => buffer.push(c),
                                         }
                                     } else {
@@ -432,6 +743,28 @@ impl Executor {
             } else if chars[0] == '#' && chars[chars.len() - 1] == '#' {
                 // Processing comments
                 self.log_print(format!("* Comment \"{}\"\n", token.replace('#', "")));
+            } else if let Some(profile) = &mut self.profile {
+                // Execute as command, measuring its time with and without nested commands
+                let outer_nested = std::mem::take(&mut profile.nested);
+                let start = Instant::now();
+                self.execute_command(token.clone());
+                let total = start.elapsed();
+
+                if let Some(profile) = &mut self.profile {
+                    let nested = std::mem::replace(&mut profile.nested, outer_nested);
+                    // Words that are not commands are pushed as strings and not counted
+                    if !std::mem::take(&mut profile.is_word) {
+                        profile.nested += total;
+                        let entry = profile.commands.entry(token).or_insert((
+                            Duration::ZERO,
+                            Duration::ZERO,
+                            0,
+                        ));
+                        entry.0 += total.saturating_sub(nested);
+                        entry.1 += total;
+                        entry.2 += 1;
+                    }
+                }
             } else {
                 // Else, execute as command
                 self.execute_command(token);
@@ -477,12 +810,34 @@ impl Executor {
             }
 
             // Remainder of division
+            // (truncated, so the result takes the sign of the dividend)
             "mod" => {
                 let b = self.pop_stack().get_number();
                 let a = self.pop_stack().get_number();
                 self.stack.push(Type::Number(a % b));
             }
 
+            // Floored division
+            "floor-div" => {
+                let b = self.pop_stack().get_number();
+                let a = self.pop_stack().get_number();
+                self.stack.push(Type::Number((a / b).floor()));
+            }
+
+            // Remainder of floored division
+            // (the result takes the sign of the divisor)
+            "floor-mod" | "mod-floor" => {
+                let b = self.pop_stack().get_number();
+                let a = self.pop_stack().get_number();
+                let r = a % b;
+                let result = if r != 0.0 && (r < 0.0) != (b < 0.0) {
+                    r + b
+                } else {
+                    r
+                };
+                self.stack.push(Type::Number(result));
+            }
+
             // Exponentiation
             "pow" => {
                 let b = self.pop_stack().get_number();
@@ -496,24 +851,110 @@ impl Executor {
                 self.stack.push(Type::Number(a.round()));
             }
 
-            // Trigonometric sine
+            // Bound a number into [min, max]
+            "clamp" => {
+                let max = self.pop_stack().get_number();
+                let min = self.pop_stack().get_number();
+                let number = self.pop_stack().get_number();
+                if min.is_nan() || max.is_nan() || min > max {
+                    self.log_print("Error! Minimum is greater than maximum\n".to_string());
+                    self.stack.push(Type::Error("clamp-range".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Number(number.clamp(min, max)));
+            }
+
+            // Round a number into pixel value of [0, 255]
+            "clamp-to-byte" => {
+                let a = self.pop_stack().get_number();
+                self.stack
+                    .push(Type::Number(a.round().clamp(0.0, 255.0) as u8 as f64));
+            }
+
+            // Linear interpolation between two numbers
+            // (t is not clamped, so values outside 0..1 extrapolate)
+            "lerp" => {
+                let t = self.pop_stack().get_number();
+                let b = self.pop_stack().get_number();
+                let a = self.pop_stack().get_number();
+                self.stack.push(Type::Number(a + (b - a) * t));
+            }
+
+            // Rescale a number from input range to output range
+            "map-range" => {
+                let out_max = self.pop_stack().get_number();
+                let out_min = self.pop_stack().get_number();
+                let in_max = self.pop_stack().get_number();
+                let in_min = self.pop_stack().get_number();
+                let value = self.pop_stack().get_number();
+                if in_max == in_min {
+                    self.log_print("Error! Input range has zero width\n".to_string());
+                    self.stack.push(Type::Error("map-range".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Number(
+                    out_min + (value - in_min) * (out_max - out_min) / (in_max - in_min),
+                ));
+            }
+
+            // Trigonometric sine (radians)
             "sin" => {
                 let number = self.pop_stack().get_number();
                 self.stack.push(Type::Number(number.sin()))
             }
 
-            // Trigonometric cosine
+            // Trigonometric cosine (radians)
             "cos" => {
                 let number = self.pop_stack().get_number();
                 self.stack.push(Type::Number(number.cos()))
             }
 
-            // Trigonometric tangent
+            // Trigonometric tangent (radians)
             "tan" => {
                 let number = self.pop_stack().get_number();
                 self.stack.push(Type::Number(number.tan()))
             }
 
+            // Inverse trigonometric sine and cosine (radians)
+            "asin" | "acos" => {
+                let number = self.pop_stack().get_number();
+                if !(-1.0..=1.0).contains(&number) {
+                    self.log_print("Error! Argument is out of domain [-1, 1]\n".to_string());
+                    self.stack.push(Type::Error("math-domain".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Number(if command == "asin" {
+                    number.asin()
+                } else {
+                    number.acos()
+                }))
+            }
+
+            // Inverse trigonometric tangent (radians)
+            "atan" => {
+                let number = self.pop_stack().get_number();
+                self.stack.push(Type::Number(number.atan()))
+            }
+
+            // Angle of the point (x, y) in radians
+            "atan2" => {
+                let y = self.pop_stack().get_number();
+                let x = self.pop_stack().get_number();
+                self.stack.push(Type::Number(y.atan2(x)))
+            }
+
+            // Convert degrees to radians
+            "deg-to-rad" => {
+                let number = self.pop_stack().get_number();
+                self.stack.push(Type::Number(number.to_radians()))
+            }
+
+            // Convert radians to degrees
+            "rad-to-deg" => {
+                let number = self.pop_stack().get_number();
+                self.stack.push(Type::Number(number.to_degrees()))
+            }
+
             // Exponential function
             "exp" => {
                 let number = self.pop_stack().get_number();
@@ -540,6 +981,29 @@ impl Executor {
                 self.stack.push(Type::Bool(!b));
             }
 
+            // Bitwise operations of integer
+            // (operands are truncated toward zero to i64)
+            "bit-and" | "bit-or" | "bit-xor" | "shift-left" | "shift-right" => {
+                let b = self.pop_stack().get_number();
+                let a = self.pop_stack().get_number();
+                if a.fract() != 0.0 || b.fract() != 0.0 {
+                    self.log_print(format!(
+                        "Warning! Operands of {command} are truncated to integer\n"
+                    ));
+                }
+                let (a, b) = (a as i64, b as i64);
+                let result = match command.as_str() {
+                    "bit-and" => a & b,
+                    "bit-or" => a | b,
+                    "bit-xor" => a ^ b,
+                    "shift-left" => a.checked_shl(b as u32).unwrap_or(0),
+                    _ => a
+                        .checked_shr(b as u32)
+                        .unwrap_or(if a < 0 { -1 } else { 0 }),
+                };
+                self.stack.push(Type::Number(result as f64));
+            }
+
             // Judge is it equal
             "equal" => {
                 let b = self.pop_stack().get_string();
@@ -682,6 +1146,107 @@ impl Executor {
                 self.stack.push(Type::List(list));
             }
 
+            // Search by regular expression, getting capture groups of each match
+            "regex-groups" => {
+                let pattern = self.pop_stack().get_string();
+                let text = self.pop_stack().get_string();
+
+                let pattern: Regex = match Regex::new(pattern.as_str()) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        self.log_print(format!("Error! {}\n", e.to_string().replace("Error", "")));
+                        self.stack.push(Type::Error("regex".to_string()));
+                        return;
+                    }
+                };
+
+                let mut list: Vec<Type> = Vec::new();
+                for i in pattern.captures_iter(text.as_str()) {
+                    // Groups that did not participate become empty string
+                    list.push(Type::List(
+                        i.iter()
+                            .map(|group| {
+                                Type::String(group.map(|m| m.as_str()).unwrap_or("").to_string())
+                            })
+                            .collect::<Vec<Type>>(),
+                    ))
+                }
+                self.stack.push(Type::List(list));
+            }
+
+            // Replacing string by regular expression
+            "replace-regex" => {
+                let replacement = self.pop_stack().get_string();
+                let pattern = self.pop_stack().get_string();
+                let text = self.pop_stack().get_string();
+
+                let pattern: Regex = match Regex::new(pattern.as_str()) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        self.log_print(format!("Error! {}\n", e.to_string().replace("Error", "")));
+                        self.stack.push(Type::Error("regex".to_string()));
+                        return;
+                    }
+                };
+
+                self.stack.push(Type::String(
+                    pattern
+                        .replace_all(text.as_str(), replacement.as_str())
+                        .to_string(),
+                ))
+            }
+
+            // Pad string on the left to the width
+            "pad-left" => {
+                let pad = self.pop_stack().get_string().chars().next().unwrap_or(' ');
+                let width = self.pop_stack().get_number() as usize;
+                let text = self.pop_stack().get_string();
+                let count = width.saturating_sub(text.chars().count());
+                self.stack
+                    .push(Type::String(pad.to_string().repeat(count) + &text));
+            }
+
+            // Pad string on the right to the width
+            "pad-right" => {
+                let pad = self.pop_stack().get_string().chars().next().unwrap_or(' ');
+                let width = self.pop_stack().get_number() as usize;
+                let text = self.pop_stack().get_string();
+                let count = width.saturating_sub(text.chars().count());
+                self.stack
+                    .push(Type::String(text + &pad.to_string().repeat(count)));
+            }
+
+            // Format number with fixed decimal places
+            "number-format" | "to-fixed" => {
+                let places = self.pop_stack().get_number();
+                let number = self.pop_stack().get_number();
+                if places < 0.0 {
+                    self.log_print("Error! Decimal places must not be negative\n".to_string());
+                    self.stack.push(Type::Error(command));
+                    return;
+                }
+                self.stack
+                    .push(Type::String(format!("{:.*}", places as usize, number)));
+            }
+
+            // Split string into list of characters
+            "to-chars" => {
+                let text = self.pop_stack().get_string();
+                self.stack.push(Type::List(
+                    text.chars()
+                        .map(|c| Type::String(c.to_string()))
+                        .collect::<Vec<Type>>(),
+                ))
+            }
+
+            // Concatenate list of characters into string
+            "from-chars" => {
+                let list = self.pop_stack().get_list();
+                self.stack.push(Type::String(
+                    list.iter().map(|x| x.get_string()).collect::<String>(),
+                ))
+            }
+
             // Commands of I/O
 
             // Write string in the file
@@ -722,10 +1287,6 @@ impl Executor {
             "print" => {
                 let a = self.pop_stack().get_string();
 
-                let a = a.replace("\\n", "\n");
-                let a = a.replace("\\t", "\t");
-                let a = a.replace("\\r", "\r");
-
                 if let Mode::Debug = self.mode {
                     println!("[Output]: {a}");
                 } else {
@@ -737,10 +1298,6 @@ impl Executor {
             "println" => {
                 let a = self.pop_stack().get_string();
 
-                let a = a.replace("\\n", "\n");
-                let a = a.replace("\\t", "\t");
-                let a = a.replace("\\r", "\r");
-
                 if let Mode::Debug = self.mode {
                     println!("[Output]: {a}");
                 } else {
@@ -765,6 +1322,51 @@ impl Executor {
                 self.evaluate_program(code)
             }
 
+            // Compose two code blocks into one running both in order
+            "compose" => {
+                let second = self.pop_stack().get_string();
+                let first = self.pop_stack().get_string();
+                self.stack.push(Type::String(format!("{first} {second}")));
+            }
+
+            // Make code block that pushes the value before running
+            // (the value is written in its displayed form, so images, videos
+            // and trackers can't be embedded as they don't parse back)
+            "prepend" => {
+                let code = self.pop_stack().get_string();
+                let value = self.pop_stack();
+                if value.has_opaque() {
+                    self.log_print("Error! The value can't be written into code\n".to_string());
+                    self.stack.push(Type::Error("prepend".to_string()));
+                    return;
+                }
+                self.stack
+                    .push(Type::String(format!("{} {code}", value.display())));
+            }
+
+            // Evaluate code with one argument, caching the result.
+            // The code should be pure, taking one value and leaving one value.
+            // Results are keyed by the code and displayed form of argument, so arguments
+            // containing images, videos or trackers are never cached as they display the same.
+            "memoize" => {
+                let code = self.pop_stack().get_string();
+                let arg = self.pop_stack();
+
+                let key = (!arg.has_opaque()).then(|| format!("{code}\n{}", arg.display()));
+                if let Some(result) = key.as_ref().and_then(|key| self.cache.get(key)) {
+                    self.stack.push(result.clone());
+                    return;
+                }
+
+                self.stack.push(arg);
+                self.evaluate_program(code);
+                let result = self.pop_stack();
+                if let Some(key) = key {
+                    self.cache.insert(key, result.clone());
+                }
+                self.stack.push(result);
+            }
+
             // Conditional branch
             "if" => {
                 let condition = self.pop_stack().get_bool(); // Condition
@@ -777,6 +1379,15 @@ impl Executor {
                 };
             }
 
+            // Choose value by condition without evaluating it
+            "select" => {
+                let condition = self.pop_stack().get_bool(); // Condition
+                let value_else = self.pop_stack(); // Value of else
+                let value_if = self.pop_stack(); // Value of If
+                self.stack
+                    .push(if condition { value_if } else { value_else });
+            }
+
             // Loop while condition is true
             "while" => {
                 let cond = self.pop_stack().get_string();
@@ -799,6 +1410,7 @@ impl Executor {
             // Exit a process
             "exit" => {
                 let status = self.pop_stack().get_number();
+                self.show_profile();
                 std::process::exit(status as i32);
             }
 
@@ -891,11 +1503,63 @@ impl Executor {
                 ));
             }
 
-            // reverse in the list
-            "reverse" => {
-                let mut list = self.pop_stack().get_list();
-                list.reverse();
-                self.stack.push(Type::List(list));
+            // reverse in the list, or characters of the string
+            "reverse" => match self.pop_stack() {
+                Type::String(s) => self.stack.push(Type::String(s.chars().rev().collect())),
+                other => {
+                    let mut list = other.get_list();
+                    list.reverse();
+                    self.stack.push(Type::List(list));
+                }
+            },
+
+            // Judge do lists have same elements regardless of order
+            // (images, videos and trackers display the same, so lists holding them are never equal)
+            "set-equal" => {
+                let b = self.pop_stack().get_list();
+                let a = self.pop_stack().get_list();
+                if a.iter().chain(&b).any(|x| x.has_opaque()) {
+                    self.stack.push(Type::Bool(false));
+                    return;
+                }
+
+                let mut b: Vec<String> = b.iter().map(|x| x.display()).collect();
+                let mut a: Vec<String> = a.iter().map(|x| x.display()).collect();
+                a.sort();
+                b.sort();
+                self.stack.push(Type::Bool(a == b));
+            }
+
+            // Set operations of lists, keeping order of first appearance
+            // (images, videos and trackers display the same, so they never match each other)
+            "union" | "intersection" | "difference" => {
+                let b = self.pop_stack().get_list();
+                let a = self.pop_stack().get_list();
+                let key = |x: &Type| (!x.has_opaque()).then(|| x.display());
+                let in_b: HashSet<String> = b.iter().filter_map(key).collect();
+
+                let candidates: Vec<Type> = match command.as_str() {
+                    "union" => a.into_iter().chain(b).collect(),
+                    "intersection" => a
+                        .into_iter()
+                        .filter(|x| key(x).is_some_and(|k| in_b.contains(&k)))
+                        .collect(),
+                    _ => a
+                        .into_iter()
+                        .filter(|x| !key(x).is_some_and(|k| in_b.contains(&k)))
+                        .collect(),
+                };
+
+                let mut seen = HashSet::new();
+                self.stack.push(Type::List(
+                    candidates
+                        .into_iter()
+                        .filter(|x| match key(x) {
+                            Some(k) => seen.insert(k),
+                            None => true,
+                        })
+                        .collect(),
+                ));
             }
 
             // Iteration for the list
@@ -913,53 +1577,322 @@ impl Executor {
                 });
             }
 
-            // Generate a range
-            "range" => {
+            // Generate a range, excluding or including the max
+            "range" | "range-inclusive" => {
                 let step = self.pop_stack().get_number();
                 let max = self.pop_stack().get_number();
                 let min = self.pop_stack().get_number();
 
+                let inclusive = command == "range-inclusive";
+                if inclusive && (!step.is_finite() || step <= 0.0) {
+                    self.log_print("Error! Step must be positive\n".to_string());
+                    self.stack.push(Type::Error("range".to_string()));
+                    return;
+                }
                 let mut range: Vec<Type> = Vec::new();
+                let mut count = 0;
                 let mut i = min;
 
-                while i < max {
+                // Compute each value from counter to avoid accumulating error,
+                // and allow small tolerance so the max is included despite rounding
+                while i < max || (inclusive && i <= max + step.abs() * 1e-9) {
                     range.push(Type::Number(i));
-                    i = step + i;
+                    count += 1;
+                    i = min + count as f64 * step;
                 }
 
                 self.stack.push(Type::List(range));
             }
 
-            // Get length of list
-            "len" => {
-                let data = self.pop_stack().get_list();
-                self.stack.push(Type::Number(data.len() as f64));
-            }
-
-            // Commands of functional programming
-
-            // Mapping a list
-            "map" => {
-                let code = self.pop_stack().get_string();
-                let vars = self.pop_stack().get_string();
+            // Split list into groups of fixed size
+            "chunk" => {
+                let size = self.pop_stack().get_number();
                 let list = self.pop_stack().get_list();
+                if size.is_nan() || size < 1.0 {
+                    self.log_print("Error! Chunk size must be 1 or more\n".to_string());
 
-                let mut result_list = Vec::new();
-                for x in list.iter() {
-                    self.memory
-                        .entry(vars.clone())
-                        .and_modify(|value| *value = x.clone())
-                        .or_insert(x.clone());
-
-                    self.evaluate_program(code.clone());
-                    result_list.push(self.pop_stack());
+                    self.stack.push(Type::Error("chunk".to_string()));
+                    return;
                 }
-
-                self.stack.push(Type::List(result_list));
+                self.stack.push(Type::List(
+                    list.chunks(size as usize)
+                        .map(|chunk| Type::List(chunk.to_vec()))
+                        .collect(),
+                ));
             }
 
-            // Filtering a list value
-            "filter" => {
+            // Make overlapping sublists of fixed size
+            "window" => {
+                let size = self.pop_stack().get_number();
+                let list = self.pop_stack().get_list();
+                if size.is_nan() || size < 1.0 {
+                    self.log_print("Error! Window size must be 1 or more\n".to_string());
+
+                    self.stack.push(Type::Error("window".to_string()));
+                    return;
+                }
+                self.stack.push(Type::List(
+                    list.windows(size as usize)
+                        .map(|window| Type::List(window.to_vec()))
+                        .collect(),
+                ));
+            }
+
+            // Transpose list of equal-length lists
+            "transpose" => {
+                let rows: Vec<Vec<Type>> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|row| row.get_list())
+                    .collect();
+                let width = rows.first().map(|row| row.len()).unwrap_or(0);
+                if rows.iter().any(|row| row.len() != width) {
+                    self.log_print("Error! Inner lists have different lengths\n".to_string());
+                    self.stack.push(Type::Error("transpose".to_string()));
+                    return;
+                }
+                self.stack.push(Type::List(
+                    (0..width)
+                        .map(|i| Type::List(rows.iter().map(|row| row[i].clone()).collect()))
+                        .collect(),
+                ));
+            }
+
+            // Alternate values of two lists, appending remainder of longer one
+            "interleave" => {
+                let b = self.pop_stack().get_list();
+                let a = self.pop_stack().get_list();
+                let mut list = Vec::with_capacity(a.len() + b.len());
+                for i in 0..a.len().max(b.len()) {
+                    list.extend(a.get(i).cloned());
+                    list.extend(b.get(i).cloned());
+                }
+                self.stack.push(Type::List(list));
+            }
+
+            // Generate list of copies of the value
+            "fill" => {
+                let count = self.pop_stack().get_number();
+                let value = self.pop_stack();
+                if count < 0.0 {
+                    self.log_print("Error! Count must not be negative\n".to_string());
+                    self.stack.push(Type::Error("fill".to_string()));
+                    return;
+                }
+                self.stack.push(Type::List(vec![value; count as usize]));
+            }
+
+            // Generate evenly spaced numbers from start to stop inclusive
+            "linspace" => {
+                let count = self.pop_stack().get_number().max(0.0) as usize;
+                let stop = self.pop_stack().get_number();
+                let start = self.pop_stack().get_number();
+
+                let step = if count > 1 {
+                    (stop - start) / (count - 1) as f64
+                } else {
+                    0.0
+                };
+                self.stack.push(Type::List(
+                    (0..count)
+                        .map(|i| {
+                            if count > 1 && i == count - 1 {
+                                Type::Number(stop)
+                            } else {
+                                Type::Number(start + i as f64 * step)
+                            }
+                        })
+                        .collect(),
+                ));
+            }
+
+            // Get index of the largest or smallest number in list
+            "argmax" | "argmin" => {
+                let list: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                if list.is_empty() {
+                    self.log_print("Error! The list is empty\n".to_string());
+                    self.stack.push(Type::Error("empty-list".to_string()));
+                    return;
+                }
+
+                // Ties resolve to the first occurrence
+                let mut best = 0;
+                for (index, value) in list.iter().enumerate() {
+                    if (command == "argmax" && *value > list[best])
+                        || (command == "argmin" && *value < list[best])
+                    {
+                        best = index;
+                    }
+                }
+                self.stack.push(Type::Number(best as f64));
+            }
+
+            // Add or multiply scalar to each number of list
+            "add-scalar" | "mul-scalar" => {
+                let scalar = self.pop_stack().get_number();
+                let list = self.pop_stack().get_list();
+                self.stack.push(Type::List(
+                    list.iter()
+                        .map(|x| {
+                            Type::Number(if command == "add-scalar" {
+                                x.get_number() + scalar
+                            } else {
+                                x.get_number() * scalar
+                            })
+                        })
+                        .collect(),
+                ));
+            }
+
+            // Bound each number of list into [min, max]
+            "clamp-list" => {
+                let max = self.pop_stack().get_number();
+                let min = self.pop_stack().get_number();
+                let list = self.pop_stack().get_list();
+                if min.is_nan() || max.is_nan() || min > max {
+                    self.log_print("Error! Minimum is greater than maximum\n".to_string());
+                    self.stack.push(Type::Error("clamp-range".to_string()));
+                    return;
+                }
+                self.stack.push(Type::List(
+                    list.iter()
+                        .map(|x| Type::Number(x.get_number().clamp(min, max)))
+                        .collect(),
+                ));
+            }
+
+            // Element-wise arithmetic of two numeric lists
+            "add-lists" | "sub-lists" | "mul-lists" | "div-lists" => {
+                let b: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                let a: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                if a.len() != b.len() {
+                    self.log_print("Error! Lists have different lengths\n".to_string());
+                    self.stack.push(Type::Error("list-length".to_string()));
+                    return;
+                }
+                if command == "div-lists" && b.contains(&0.0) {
+                    self.log_print("Error! Division by zero\n".to_string());
+                    self.stack.push(Type::Error("division-by-zero".to_string()));
+                    return;
+                }
+                self.stack.push(Type::List(
+                    a.iter()
+                        .zip(b.iter())
+                        .map(|(a, b)| {
+                            Type::Number(match command.as_str() {
+                                "add-lists" => a + b,
+                                "sub-lists" => a - b,
+                                "mul-lists" => a * b,
+                                _ => a / b,
+                            })
+                        })
+                        .collect(),
+                ));
+            }
+
+            // Dot product of two numeric lists
+            "dot-product" => {
+                let b: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                let a: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                if a.len() != b.len() {
+                    self.log_print("Error! Lists have different lengths\n".to_string());
+                    self.stack.push(Type::Error("list-length".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Number(
+                    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum(),
+                ));
+            }
+
+            // Euclidean norm of numeric list
+            "magnitude" => {
+                let list = self.pop_stack().get_list();
+                self.stack.push(Type::Number(
+                    list.iter()
+                        .map(|x| x.get_number().powi(2))
+                        .sum::<f64>()
+                        .sqrt(),
+                ));
+            }
+
+            // Get length of list
+            "len" => {
+                let data = self.pop_stack().get_list();
+                self.stack.push(Type::Number(data.len() as f64));
+            }
+
+            // Commands of functional programming
+
+            // Mapping a list
+            "map" => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+
+                let mut result_list = Vec::new();
+                for x in list.iter() {
+                    self.memory
+                        .entry(vars.clone())
+                        .and_modify(|value| *value = x.clone())
+                        .or_insert(x.clone());
+
+                    self.evaluate_program(code.clone());
+                    result_list.push(self.pop_stack());
+                }
+
+                self.stack.push(Type::List(result_list));
+            }
+
+            // Mapping a list and flattening the results
+            "flat-map" => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+
+                let result_list = self.keep_variable(&vars, |executor| {
+                    let mut result_list = Vec::new();
+                    for x in list.iter() {
+                        executor.memory.insert(vars.clone(), x.clone());
+                        executor.evaluate_program(code.clone());
+                        match executor.pop_stack() {
+                            Type::List(items) => result_list.extend(items),
+                            other => result_list.push(other),
+                        }
+                    }
+                    result_list
+                });
+
+                self.stack.push(Type::List(result_list));
+            }
+
+            // Filtering a list value
+            "filter" => {
                 let code = self.pop_stack().get_string();
                 let vars = self.pop_stack().get_string();
                 let list = self.pop_stack().get_list();
@@ -981,6 +1914,106 @@ impl Executor {
                 self.stack.push(Type::List(result_list));
             }
 
+            // Splitting a list into passing and failing values
+            "partition" => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+
+                let (passing, failing) = self.keep_variable(&vars, |executor| {
+                    let (mut passing, mut failing) = (Vec::new(), Vec::new());
+                    for x in list.iter() {
+                        executor.memory.insert(vars.clone(), x.clone());
+                        executor.evaluate_program(code.clone());
+                        if executor.pop_stack().get_bool() {
+                            passing.push(x.clone());
+                        } else {
+                            failing.push(x.clone());
+                        }
+                    }
+                    (passing, failing)
+                });
+
+                self.stack.push(Type::List(passing));
+                self.stack.push(Type::List(failing));
+            }
+
+            // Judge does predicate hold for every value or for any value
+            "all?" | "any?" => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+
+                // all? stops on the first false, any? stops on the first true
+                let target = command == "any?";
+                let found = self.keep_variable(&vars, |executor| {
+                    list.iter().any(|x| {
+                        executor.memory.insert(vars.clone(), x.clone());
+                        executor.evaluate_program(code.clone());
+                        executor.pop_stack().get_bool() == target
+                    })
+                });
+
+                self.stack.push(Type::Bool(found == target));
+            }
+
+            // Get first value satisfying predicate
+            "find-first" => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+
+                let found = self.keep_variable(&vars, |executor| {
+                    list.into_iter().find(|x| {
+                        executor.memory.insert(vars.clone(), x.clone());
+                        executor.evaluate_program(code.clone());
+                        executor.pop_stack().get_bool()
+                    })
+                });
+
+                match found {
+                    Some(value) => self.stack.push(value),
+                    None => {
+                        self.log_print("Error! No value satisfies the predicate\n".to_string());
+                        self.stack.push(Type::Error("not-found".to_string()));
+                    }
+                }
+            }
+
+            // Get value with the largest or smallest key
+            "max-by" | "min-by" => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+
+                let best = self.keep_variable(&vars, |executor| {
+                    let mut best: Option<(f64, Type)> = None;
+                    for x in list.into_iter() {
+                        executor.memory.insert(vars.clone(), x.clone());
+                        executor.evaluate_program(code.clone());
+                        let key = executor.pop_stack().get_number();
+
+                        let better = match &best {
+                            None => true,
+                            Some((best_key, _)) if command == "max-by" => key > *best_key,
+                            Some((best_key, _)) => key < *best_key,
+                        };
+                        if better {
+                            best = Some((key, x));
+                        }
+                    }
+                    best
+                });
+
+                match best {
+                    Some((_, value)) => self.stack.push(value),
+                    None => {
+                        self.log_print("Error! The list is empty\n".to_string());
+                        self.stack.push(Type::Error("empty-list".to_string()));
+                    }
+                }
+            }
+
             // Generate value from list
             "reduce" => {
                 let code = self.pop_stack().get_string();
@@ -1019,6 +2052,62 @@ impl Executor {
                     .or_insert(Type::String("".to_string()));
             }
 
+            // Grouping list values by key
+            "group-by" => {
+                let code = self.pop_stack().get_string();
+                let vars = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+
+                let groups = self.keep_variable(&vars, |executor| {
+                    let mut groups: Vec<(String, Vec<Type>)> = Vec::new();
+                    for x in list.iter() {
+                        executor.memory.insert(vars.clone(), x.clone());
+                        executor.evaluate_program(code.clone());
+                        let key = executor.pop_stack().get_string();
+
+                        match groups.iter_mut().find(|(k, _)| *k == key) {
+                            Some((_, group)) => group.push(x.clone()),
+                            None => groups.push((key, vec![x.clone()])),
+                        }
+                    }
+                    groups
+                });
+
+                self.stack.push(Type::List(
+                    groups
+                        .into_iter()
+                        .map(|(key, group)| Type::List(vec![Type::String(key), Type::List(group)]))
+                        .collect(),
+                ));
+            }
+
+            // Generate list of running accumulated values
+            "scan" => {
+                let code = self.pop_stack().get_string();
+                let now = self.pop_stack().get_string();
+                let init = self.pop_stack();
+                let acc = self.pop_stack().get_string();
+                let list = self.pop_stack().get_list();
+
+                let result_list = self.keep_variable(&acc, |executor| {
+                    executor.keep_variable(&now, |executor| {
+                        let mut result_list = vec![init.clone()];
+                        executor.memory.insert(acc.clone(), init);
+
+                        for x in list.iter() {
+                            executor.memory.insert(now.clone(), x.clone());
+                            executor.evaluate_program(code.clone());
+                            let result = executor.pop_stack();
+                            executor.memory.insert(acc.clone(), result.clone());
+                            result_list.push(result);
+                        }
+                        result_list
+                    })
+                });
+
+                self.stack.push(Type::List(result_list));
+            }
+
             // Commands of memory manage
 
             // Pop in the stack
@@ -1037,6 +2126,18 @@ impl Executor {
                 self.stack.push(Type::List(self.stack.clone()));
             }
 
+            // Move whole stack into a list
+            "collect" => {
+                let list = std::mem::take(&mut self.stack);
+                self.stack.push(Type::List(list));
+            }
+
+            // Push each value of list onto the stack
+            "spread" => {
+                let list = self.pop_stack().get_list();
+                self.stack.extend(list);
+            }
+
             // Define variable at memory
             "var" => {
                 let name = self.pop_stack().get_string();
@@ -1057,11 +2158,77 @@ impl Executor {
                     Type::List(_) => "list".to_string(),
                     Type::Error(_) => "error".to_string(),
                     Type::Image(_) => "image".to_string(),
+                    Type::Video(_) => "video".to_string(),
+                    #[cfg(feature = "tracker")]
+                    Type::Tracker(_) => "tracker".to_string(),
                 };
 
                 self.stack.push(Type::String(result));
             }
 
+            // Judge data type of value
+            "is-number" | "is-string" | "is-bool" | "is-list" | "is-error" | "is-image"
+            | "is-video" => {
+                let value = self.pop_stack();
+                let result = matches!(
+                    (command.as_str(), value),
+                    ("is-number", Type::Number(_))
+                        | ("is-string", Type::String(_))
+                        | ("is-bool", Type::Bool(_))
+                        | ("is-list", Type::List(_))
+                        | ("is-error", Type::Error(_))
+                        | ("is-image", Type::Image(_))
+                        | ("is-video", Type::Video(_))
+                );
+                self.stack.push(Type::Bool(result));
+            }
+
+            // Judge is value a tracker
+            #[cfg(feature = "tracker")]
+            "is-tracker" => {
+                let value = self.pop_stack();
+                self.stack
+                    .push(Type::Bool(matches!(value, Type::Tracker(_))));
+            }
+
+            // Use fallback value instead of error value
+            "default" => {
+                let fallback = self.pop_stack();
+                let value = self.pop_stack();
+                if let Type::Error(_) = value {
+                    self.stack.push(fallback);
+                } else {
+                    self.stack.push(value);
+                }
+            }
+
+            // Get message of error value
+            "error-message" => {
+                let message = match self.pop_stack() {
+                    Type::Error(err) => err,
+                    _ => String::new(),
+                };
+                self.stack.push(Type::String(message));
+            }
+
+            // Raise error value with the message
+            "throw" => {
+                let message = self.pop_stack().get_string();
+                self.stack.push(Type::Error(message));
+            }
+
+            // Parse string as number, failing on invalid input
+            "parse-number" => {
+                let text = self.pop_stack().get_string();
+                match text.trim().parse::<f64>() {
+                    Ok(number) => self.stack.push(Type::Number(number)),
+                    Err(e) => {
+                        self.log_print(format!("Error! {e}\n"));
+                        self.stack.push(Type::Error("parse-number".to_string()));
+                    }
+                }
+            }
+
             // Explicit data type casting
             "cast" => {
                 let types = self.pop_stack().get_string();
@@ -1135,7 +2302,7 @@ impl Executor {
             // Show image using GUI window
             "show-image" => {
                 //Display the image
-                let window_name: &str = "Image Window";
+                let window_name: &str = &self.window.clone();
                 highgui::named_window(window_name, highgui::WINDOW_NORMAL).unwrap();
                 highgui::imshow(window_name, &self.pop_stack().get_image()).unwrap();
 
@@ -1143,20 +2310,134 @@ impl Executor {
                 highgui::wait_key(0).unwrap();
             }
 
-            // Modify image to grayscale
-            "to-grayscale" => {
-                fn to_grayscale(img: &Mat) -> Mat {
-                    let mut gray_img = Mat::default();
-                    imgproc::cvt_color(img, &mut gray_img, imgproc::COLOR_BGR2GRAY, 0).unwrap();
-                    gray_img
+            // Paint red strokes on image by dragging the mouse, until a key is pressed
+            "paint-canvas" => {
+                let mut img = to_bgr(&self.pop_stack().get_image());
+                if img.empty() {
+                    self.log_print("Error! There is no image to paint\n".to_string());
+                    self.stack.push(Type::Error("paint-canvas".to_string()));
+                    return;
                 }
+                let window_name: &str = &self.window.clone();
+                highgui::named_window(window_name, highgui::WINDOW_NORMAL).unwrap();
 
-                let img = &self.pop_stack().get_image();
-                self.stack.push(Type::Image(to_grayscale(img)))
+                // The callback records pending stroke segments and the last dragged point,
+                // and the event loop below draws them
+                type Strokes = (Option<core::Point>, Vec<(core::Point, core::Point)>);
+                let strokes: Arc<Mutex<Strokes>> = Arc::new(Mutex::new((None, Vec::new())));
+                let recorder = Arc::clone(&strokes);
+                highgui::set_mouse_callback(
+                    window_name,
+                    Some(Box::new(move |event: i32, x: i32, y: i32, flags: i32| {
+                        let mut strokes = recorder.lock().unwrap();
+                        let point = core::Point::new(x, y);
+                        match event {
+                            highgui::EVENT_LBUTTONDOWN => strokes.0 = Some(point),
+                            highgui::EVENT_LBUTTONUP => strokes.0 = None,
+                            highgui::EVENT_MOUSEMOVE
+                                if flags & highgui::EVENT_FLAG_LBUTTON != 0 =>
+                            {
+                                if let Some(last) = strokes.0 {
+                                    strokes.1.push((last, point));
+                                }
+                                strokes.0 = Some(point);
+                            }
+                            _ => {}
+                        }
+                    })),
+                )
+                .unwrap();
+
+                loop {
+                    let segments: Vec<_> = strokes.lock().unwrap().1.drain(..).collect();
+                    for (from, to) in segments {
+                        imgproc::line(
+                            &mut img,
+                            from,
+                            to,
+                            core::Scalar::new(0.0, 0.0, 255.0, 0.0),
+                            3,
+                            imgproc::LINE_AA,
+                            0,
+                        )
+                        .unwrap();
+                    }
+                    highgui::imshow(window_name, &img).unwrap();
+                    if highgui::wait_key(20).unwrap() >= 0 {
+                        break;
+                    }
+                }
+                highgui::set_mouse_callback(window_name, None).unwrap();
+                self.stack.push(Type::Image(img))
             }
 
-            // Modify image to invert its color
-            "invert-color" => {
+            // Show list of images in a grid using GUI window
+            "show-images" => {
+                let images: Vec<Mat> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_image())
+                    .collect();
+                if images.is_empty() || images.iter().any(|img| img.empty()) {
+                    self.log_print("Error! There are no images to show\n".to_string());
+                    self.stack.push(Type::Error("show-images".to_string()));
+                    return;
+                }
+
+                // Choose roughly square grid
+                let columns = (images.len() as f64).sqrt().ceil() as usize;
+                let window_name: &str = &self.window;
+                highgui::named_window(window_name, highgui::WINDOW_NORMAL).unwrap();
+                highgui::imshow(window_name, &image_grid(&images, columns)).unwrap();
+
+                // Wait for a key press
+                highgui::wait_key(0).unwrap();
+            }
+
+            // Show image in titled window, closing it after a key press,
+            // or run code that shows images into titled window, closing it at the end
+            "with-window" => {
+                let title = self.pop_stack().get_string();
+                highgui::named_window(&title, highgui::WINDOW_NORMAL).unwrap();
+                match self.pop_stack() {
+                    Type::Image(img) => {
+                        highgui::imshow(&title, &img).unwrap();
+
+                        // Wait for a key press and return its code
+                        let key = highgui::wait_key(0).unwrap();
+                        self.stack.push(Type::Number(key as f64));
+                    }
+                    code => {
+                        let outer = std::mem::replace(&mut self.window, title.clone());
+                        self.evaluate_program(code.get_string());
+                        self.window = outer;
+                    }
+                }
+
+                // The code may have closed the window already
+                let _ = highgui::destroy_window(&title);
+            }
+
+            // Close all GUI windows
+            "close-windows" => {
+                highgui::destroy_all_windows().unwrap();
+            }
+
+            // Modify image to grayscale
+            "to-grayscale" => {
+                fn to_grayscale(img: &Mat) -> Mat {
+                    let mut gray_img = Mat::default();
+                    imgproc::cvt_color(img, &mut gray_img, imgproc::COLOR_BGR2GRAY, 0).unwrap();
+                    gray_img
+                }
+
+                let img = &self.pop_stack().get_image();
+                self.stack.push(Type::Image(to_grayscale(img)))
+            }
+
+            // Modify image to invert its color
+            "invert-color" => {
                 fn invert_color(img: &Mat) -> Mat {
                     let mut inverted_img = Mat::default();
                     core::bitwise_not(img, &mut inverted_img, &core::no_array()).unwrap();
@@ -1349,21 +2630,1569 @@ impl Executor {
                 self.stack.push(Type::Image(to_sharpe(img, level)))
             }
 
-            // If it is not recognized as a command, use it as a string.
-            _ => self.stack.push(Type::String(command)),
-        }
-    }
+            // Extract a single channel of image as grayscale (0=B, 1=G, 2=R for color image)
+            "channel-extract" => {
+                fn extract_channel(img: &Mat, index: i32) -> Mat {
+                    let mut channel_img = Mat::default();
+                    core::extract_channel(img, &mut channel_img, index).unwrap();
+                    channel_img
+                }
 
-    /// Pop stack's top value
-    fn pop_stack(&mut self) -> Type {
-        if let Some(value) = self.stack.pop() {
-            value
-        } else {
-            self.log_print(
-                "Error! There are not enough values on the stack. returns default value\n"
-                    .to_string(),
-            );
-            Type::String("".to_string())
-        }
+                let index = self.pop_stack().get_number();
+                let img = &self.pop_stack().get_image();
+                if index < 0.0 || index as i32 >= img.channels() {
+                    self.log_print("Error! Channel index is out of range\n".to_string());
+                    self.stack.push(Type::Error("channel-index".to_string()));
+                    return;
+                }
+                self.stack
+                    .push(Type::Image(extract_channel(img, index as i32)))
+            }
+
+            // Modify image to pixelate like mosaic
+            "pixelate" => {
+                fn pixelate(img: &Mat, block: i32) -> Mat {
+                    let size = img.size().unwrap();
+                    let mut small_img = Mat::default();
+                    resize(
+                        img,
+                        &mut small_img,
+                        core::Size::new((size.width / block).max(1), (size.height / block).max(1)),
+                        0.0,
+                        0.0,
+                        imgproc::INTER_LINEAR,
+                    )
+                    .unwrap();
+
+                    let mut pixelated_img = Mat::default();
+                    resize(
+                        &small_img,
+                        &mut pixelated_img,
+                        size,
+                        0.0,
+                        0.0,
+                        imgproc::INTER_NEAREST,
+                    )
+                    .unwrap();
+                    pixelated_img
+                }
+
+                let block = self.pop_stack().get_number();
+                let img = &self.pop_stack().get_image();
+                if block.is_nan() || block < 1.0 {
+                    self.log_print("Error! Block size must be 1 or more\n".to_string());
+                    self.stack.push(Type::Error("pixelate".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Image(pixelate(img, block as i32)))
+            }
+
+            // Modify image to mapping its values by lookup table
+            "apply-lut" => {
+                let table: Vec<u8> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number().round().clamp(0.0, 255.0) as u8)
+                    .collect();
+                let img = &self.pop_stack().get_image();
+                if table.len() != 256 {
+                    self.log_print("Error! Lookup table must have 256 entries\n".to_string());
+                    self.stack.push(Type::Error("apply-lut".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Image(apply_lut(img, table)))
+            }
+
+            // Modify image to sepia tone
+            "sepia" => {
+                fn sepia(img: &Mat) -> Mat {
+                    let color_img = to_bgr(img);
+
+                    // Sepia matrix for BGR channel order
+                    let kernel = Mat::from_slice_2d(&[
+                        [0.131f64, 0.534f64, 0.272f64],
+                        [0.168f64, 0.686f64, 0.349f64],
+                        [0.189f64, 0.769f64, 0.393f64],
+                    ])
+                    .unwrap();
+                    let mut sepia_img = Mat::default();
+                    core::transform(&color_img, &mut sepia_img, &kernel).unwrap();
+                    sepia_img
+                }
+
+                let img = &self.pop_stack().get_image();
+                self.stack.push(Type::Image(sepia(img)))
+            }
+
+            // Modify image to mix its color channels by matrix
+            "color-matrix" => {
+                fn color_matrix(img: &Mat, matrix: Vec<Vec<f64>>) -> Mat {
+                    let kernel = Mat::from_slice_2d(&matrix).unwrap();
+                    let mut result_img = Mat::default();
+                    core::transform(&to_bgr(img), &mut result_img, &kernel).unwrap();
+                    result_img
+                }
+
+                let matrix: Vec<Vec<f64>> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|row| row.get_list().iter().map(|x| x.get_number()).collect())
+                    .collect();
+                let img = &self.pop_stack().get_image();
+                let cols = matrix.first().map(|row| row.len()).unwrap_or(0);
+
+                if matrix.len() != 3
+                    || (cols != 3 && cols != 4)
+                    || matrix.iter().any(|row| row.len() != cols)
+                {
+                    self.log_print("Error! Color matrix must be 3x3 or 3x4\n".to_string());
+                    self.stack.push(Type::Error("color-matrix".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Image(color_matrix(img, matrix)))
+            }
+
+            // Modify image to reduce its color levels
+            "posterize" => {
+                let levels = self.pop_stack().get_number() as i32;
+                let img = &self.pop_stack().get_image();
+                if !(2..=256).contains(&levels) {
+                    self.log_print("Error! Levels must be between 2 and 256\n".to_string());
+                    self.stack.push(Type::Error("posterize".to_string()));
+                    return;
+                }
+                let table: Vec<u8> = (0..256)
+                    .map(|i| {
+                        let band = (i * levels / 256) as f64;
+                        (band * 255.0 / (levels - 1) as f64).round() as u8
+                    })
+                    .collect();
+                self.stack.push(Type::Image(apply_lut(img, table)))
+            }
+
+            // Modify image to cartoon style
+            "cartoonify" => {
+                fn cartoonify(img: &Mat) -> Mat {
+                    let color_img = to_bgr(img);
+
+                    // Smooth colors while keeping edges
+                    let mut smooth_img = Mat::default();
+                    imgproc::bilateral_filter(
+                        &color_img,
+                        &mut smooth_img,
+                        9,
+                        250.0,
+                        250.0,
+                        core::BORDER_DEFAULT,
+                    )
+                    .unwrap();
+
+                    // Detect bold edges from grayscale
+                    let mut gray_img = Mat::default();
+                    imgproc::cvt_color(&color_img, &mut gray_img, imgproc::COLOR_BGR2GRAY, 0)
+                        .unwrap();
+                    let mut blurred_img = Mat::default();
+                    imgproc::median_blur(&gray_img, &mut blurred_img, 7).unwrap();
+                    let mut edges = Mat::default();
+                    imgproc::adaptive_threshold(
+                        &blurred_img,
+                        &mut edges,
+                        255.0,
+                        imgproc::ADAPTIVE_THRESH_MEAN_C,
+                        imgproc::THRESH_BINARY,
+                        9,
+                        2.0,
+                    )
+                    .unwrap();
+
+                    let mut cartoon_img = Mat::default();
+                    core::bitwise_and(
+                        &smooth_img,
+                        &to_bgr(&edges),
+                        &mut cartoon_img,
+                        &core::no_array(),
+                    )
+                    .unwrap();
+                    cartoon_img
+                }
+
+                let img = &self.pop_stack().get_image();
+                self.stack.push(Type::Image(cartoonify(img)))
+            }
+
+            // Modify image to binary mask using Otsu's threshold
+            "threshold-otsu-mask" => {
+                fn threshold_otsu_mask(img: &Mat) -> Mat {
+                    let mut mask = Mat::default();
+                    imgproc::threshold(
+                        &to_gray(img),
+                        &mut mask,
+                        0.0,
+                        255.0,
+                        imgproc::THRESH_BINARY | imgproc::THRESH_OTSU,
+                    )
+                    .unwrap();
+                    mask
+                }
+
+                let img = &self.pop_stack().get_image();
+                self.stack.push(Type::Image(threshold_otsu_mask(img)))
+            }
+
+            // Count non-zero pixels of single channel image
+            "count-nonzero" => {
+                let img = self.pop_stack().get_image();
+                if img.channels() != 1 {
+                    self.log_print("Error! Image must have a single channel\n".to_string());
+                    self.stack.push(Type::Error("count-nonzero".to_string()));
+                    return;
+                }
+                let count = core::count_non_zero(&img).unwrap();
+                self.stack.push(Type::Number(count as f64))
+            }
+
+            // Get image size as [width height]
+            "image-size" => {
+                let img = self.pop_stack().get_image();
+                self.stack.push(Type::List(vec![
+                    Type::Number(img.cols() as f64),
+                    Type::Number(img.rows() as f64),
+                ]))
+            }
+
+            // Modify image to rotate by degrees, expanding canvas to fit
+            "rotate-bound" => {
+                let angle = self.pop_stack().get_number().to_radians();
+                let img = &self.pop_stack().get_image();
+
+                let (width, height) = (img.cols() as f64, img.rows() as f64);
+                let (a, b) = (angle.cos(), angle.sin());
+                let new_width = (height * b.abs() + width * a.abs()).round();
+                let new_height = (height * a.abs() + width * b.abs()).round();
+
+                // Same matrix as get_rotation_matrix_2d, moved to the center of new canvas
+                let (cx, cy) = (width / 2.0, height / 2.0);
+                let matrix = Mat::from_slice_2d(&[
+                    [a, b, (1.0 - a) * cx - b * cy + new_width / 2.0 - cx],
+                    [-b, a, b * cx + (1.0 - a) * cy + new_height / 2.0 - cy],
+                ])
+                .unwrap();
+
+                let size = core::Size::new(new_width as i32, new_height as i32);
+                self.stack.push(Type::Image(warp_image(img, &matrix, size)));
+                self.stack.push(Type::List(vec![
+                    Type::Number(new_width),
+                    Type::Number(new_height),
+                ]))
+            }
+
+            // Combine list of images into a grid montage, padding the last row
+            "grid-montage" | "image-grid" => {
+                let columns = self.pop_stack().get_number();
+                let images: Vec<Mat> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_image())
+                    .collect();
+                if columns.is_nan()
+                    || columns < 1.0
+                    || images.is_empty()
+                    || images.iter().any(|img| img.empty())
+                {
+                    self.log_print(
+                        "Error! Montage needs images and 1 or more columns\n".to_string(),
+                    );
+                    self.stack.push(Type::Error(command));
+                    return;
+                }
+                self.stack
+                    .push(Type::Image(image_grid(&images, columns as usize)))
+            }
+
+            // Join list of images side by side or top to bottom,
+            // scaling each to the height or width of the first one
+            "hstack" | "vstack" => {
+                fn stack_images(images: &[Mat], horizontal: bool) -> Mat {
+                    let first = images[0].size().unwrap();
+                    let mut scaled = core::Vector::<Mat>::new();
+                    for img in images {
+                        let size = img.size().unwrap();
+                        let target = if horizontal {
+                            let width =
+                                size.width as f64 * first.height as f64 / size.height as f64;
+                            core::Size::new((width.round() as i32).max(1), first.height)
+                        } else {
+                            let height =
+                                size.height as f64 * first.width as f64 / size.width as f64;
+                            core::Size::new(first.width, (height.round() as i32).max(1))
+                        };
+                        let mut scaled_img = Mat::default();
+                        resize(
+                            &to_bgr(img),
+                            &mut scaled_img,
+                            target,
+                            0.0,
+                            0.0,
+                            imgproc::INTER_LINEAR,
+                        )
+                        .unwrap();
+                        scaled.push(scaled_img);
+                    }
+
+                    let mut stacked_img = Mat::default();
+                    if horizontal {
+                        core::hconcat(&scaled, &mut stacked_img).unwrap();
+                    } else {
+                        core::vconcat(&scaled, &mut stacked_img).unwrap();
+                    }
+                    stacked_img
+                }
+
+                let images: Vec<Mat> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_image())
+                    .collect();
+                if images.is_empty() || images.iter().any(|img| img.empty()) {
+                    self.log_print("Error! There are no images to join\n".to_string());
+                    self.stack.push(Type::Error(command));
+                    return;
+                }
+                self.stack
+                    .push(Type::Image(stack_images(&images, command == "hstack")))
+            }
+
+            // Find minimum and maximum values of image with their locations
+            // as [min max [min-x min-y] [max-x max-y]]
+            "min-max-loc" => {
+                let img = to_gray(&self.pop_stack().get_image());
+                let (mut min_val, mut max_val) = (0.0, 0.0);
+                let (mut min_loc, mut max_loc) = (core::Point::default(), core::Point::default());
+                core::min_max_loc(
+                    &img,
+                    Some(&mut min_val),
+                    Some(&mut max_val),
+                    Some(&mut min_loc),
+                    Some(&mut max_loc),
+                    &core::no_array(),
+                )
+                .unwrap();
+                self.stack.push(Type::List(vec![
+                    Type::Number(min_val),
+                    Type::Number(max_val),
+                    Type::List(vec![
+                        Type::Number(min_loc.x as f64),
+                        Type::Number(min_loc.y as f64),
+                    ]),
+                    Type::List(vec![
+                        Type::Number(max_loc.x as f64),
+                        Type::Number(max_loc.y as f64),
+                    ]),
+                ]))
+            }
+
+            // Modify image to binary mask of pixels within [low, high]
+            "threshold-range" => {
+                fn threshold_range(img: &Mat, low: f64, high: f64) -> Mat {
+                    let mut mask = Mat::default();
+                    core::in_range(
+                        &to_gray(img),
+                        &core::Scalar::all(low),
+                        &core::Scalar::all(high),
+                        &mut mask,
+                    )
+                    .unwrap();
+                    mask
+                }
+
+                let high = self.pop_stack().get_number();
+                let low = self.pop_stack().get_number();
+                let img = &self.pop_stack().get_image();
+                self.stack
+                    .push(Type::Image(threshold_range(img, low, high)))
+            }
+
+            // Count distinct colors of image
+            "count-colors" => {
+                fn count_colors(img: &Mat) -> usize {
+                    // Large images are downsampled to at most 512x512 pixels,
+                    // so the count is approximate for them
+                    let mut color_img = to_bgr(img);
+                    let limit = 512.0 * 512.0;
+                    let area = (color_img.cols() * color_img.rows()) as f64;
+                    if area > limit {
+                        let scale = (limit / area).sqrt();
+                        let mut small_img = Mat::default();
+                        resize(
+                            &color_img,
+                            &mut small_img,
+                            core::Size::default(),
+                            scale,
+                            scale,
+                            imgproc::INTER_NEAREST,
+                        )
+                        .unwrap();
+                        color_img = small_img;
+                    }
+
+                    color_img
+                        .data_typed::<core::Vec3b>()
+                        .unwrap()
+                        .iter()
+                        .map(|pixel| pixel.0)
+                        .collect::<HashSet<[u8; 3]>>()
+                        .len()
+                }
+
+                let img = &self.pop_stack().get_image();
+                self.stack.push(Type::Number(count_colors(img) as f64))
+            }
+
+            // Modify image by affine transform mapping three points to three points
+            "affine-transform" => {
+                let size = self.pop_stack().get_list();
+                let dst = to_points(&self.pop_stack().get_list());
+                let src = to_points(&self.pop_stack().get_list());
+                let img = &self.pop_stack().get_image();
+                let (src, dst) = match (src, dst, size.as_slice()) {
+                    (Some(src), Some(dst), [_, _]) if src.len() == 3 && dst.len() == 3 => {
+                        (src, dst)
+                    }
+                    _ => {
+                        self.log_print(
+                            "Error! Affine transform needs three point pairs\n".to_string(),
+                        );
+                        self.stack.push(Type::Error("affine-transform".to_string()));
+                        return;
+                    }
+                };
+
+                let matrix = imgproc::get_affine_transform(
+                    &core::Vector::<core::Point2f>::from(src),
+                    &core::Vector::<core::Point2f>::from(dst),
+                )
+                .unwrap();
+                let size =
+                    core::Size::new(size[0].get_number() as i32, size[1].get_number() as i32);
+                self.stack.push(Type::Image(warp_image(img, &matrix, size)))
+            }
+
+            // Modify image to shift by pixel offsets
+            "translate-image" => {
+                let dy = self.pop_stack().get_number();
+                let dx = self.pop_stack().get_number();
+                let img = &self.pop_stack().get_image();
+                let matrix = Mat::from_slice_2d(&[[1.0, 0.0, dx], [0.0, 1.0, dy]]).unwrap();
+                self.stack
+                    .push(Type::Image(warp_image(img, &matrix, img.size().unwrap())))
+            }
+
+            // Simplify contour to polygon
+            "approx-poly" => {
+                let epsilon = self.pop_stack().get_number();
+                let contour = match to_points(&self.pop_stack().get_list()) {
+                    Some(points) if !points.is_empty() => {
+                        core::Vector::<core::Point2f>::from(points)
+                    }
+                    _ => {
+                        self.log_print("Error! Contour must be list of [x y] points\n".to_string());
+                        self.stack.push(Type::Error("approx-poly".to_string()));
+                        return;
+                    }
+                };
+
+                let mut polygon = core::Vector::<core::Point2f>::new();
+                imgproc::approx_poly_dp(&contour, &mut polygon, epsilon, true).unwrap();
+                self.stack.push(from_points(&polygon))
+            }
+
+            // Get centroid and area of contour or binary image as [cx cy area]
+            "moments" => {
+                let moments = match self.pop_stack() {
+                    Type::Image(img) => Some(imgproc::moments(&to_gray(&img), true).unwrap()),
+                    other => to_points(&other.get_list()).map(|points| {
+                        imgproc::moments(&core::Vector::<core::Point2f>::from(points), false)
+                            .unwrap()
+                    }),
+                };
+                let moments = match moments {
+                    Some(moments) if moments.m00 != 0.0 => moments,
+                    _ => {
+                        self.log_print("Error! Shape has no area\n".to_string());
+                        self.stack.push(Type::Error("moments".to_string()));
+                        return;
+                    }
+                };
+                self.stack.push(Type::List(vec![
+                    Type::Number(moments.m10 / moments.m00),
+                    Type::Number(moments.m01 / moments.m00),
+                    Type::Number(moments.m00),
+                ]))
+            }
+
+            // Draw keypoints of [x y size angle] format on image
+            "draw-keypoints" => {
+                let keypoints = self.pop_stack().get_list();
+                let mut img = to_bgr(&self.pop_stack().get_image());
+                for keypoint in keypoints {
+                    let keypoint: Vec<f64> =
+                        keypoint.get_list().iter().map(|x| x.get_number()).collect();
+                    if keypoint.len() < 2 {
+                        continue;
+                    }
+                    let center = core::Point::new(keypoint[0] as i32, keypoint[1] as i32);
+                    let radius = keypoint
+                        .get(2)
+                        .map(|size| size / 2.0)
+                        .unwrap_or(1.0)
+                        .max(1.0);
+                    imgproc::circle(
+                        &mut img,
+                        center,
+                        radius as i32,
+                        core::Scalar::new(0.0, 255.0, 0.0, 0.0),
+                        1,
+                        imgproc::LINE_AA,
+                        0,
+                    )
+                    .unwrap();
+                }
+                self.stack.push(Type::Image(img))
+            }
+
+            // Draw polygon of [x y] points on image (filled when thickness is -1)
+            "draw-polygon" => {
+                let thickness = self.pop_stack().get_number() as i32;
+                let closed = self.pop_stack().get_bool();
+                let points = to_points(&self.pop_stack().get_list());
+                let img = self.pop_stack().get_image();
+                if thickness != -1 && thickness < 1 {
+                    self.log_print("Error! Thickness must be -1 or 1 or more\n".to_string());
+                    self.stack.push(Type::Error("draw-polygon".to_string()));
+                    return;
+                }
+                let points = match points {
+                    Some(points) if points.len() >= 2 => points,
+                    _ => {
+                        self.log_print(
+                            "Error! Polygon needs two or more [x y] points\n".to_string(),
+                        );
+                        self.stack.push(Type::Error("draw-polygon".to_string()));
+                        return;
+                    }
+                };
+                let polygon: core::Vector<core::Vector<core::Point>> =
+                    core::Vector::from(vec![points
+                        .iter()
+                        .map(|point| core::Point::new(point.x as i32, point.y as i32))
+                        .collect::<core::Vector<core::Point>>()]);
+
+                let mut img = to_bgr(&img);
+                let color = core::Scalar::new(0.0, 255.0, 0.0, 0.0);
+
+                if thickness == -1 {
+                    imgproc::fill_poly(
+                        &mut img,
+                        &polygon,
+                        color,
+                        imgproc::LINE_AA,
+                        0,
+                        core::Point::default(),
+                    )
+                    .unwrap();
+                } else {
+                    imgproc::polylines(
+                        &mut img,
+                        &polygon,
+                        closed,
+                        color,
+                        thickness,
+                        imgproc::LINE_AA,
+                        0,
+                    )
+                    .unwrap();
+                }
+                self.stack.push(Type::Image(img))
+            }
+
+            // Label connected components of binary image as list of [x y w h area]
+            "connected-components" => {
+                let img = to_gray(&self.pop_stack().get_image());
+                let (mut labels, mut stats, mut centroids) =
+                    (Mat::default(), Mat::default(), Mat::default());
+                let count = imgproc::connected_components_with_stats(
+                    &img,
+                    &mut labels,
+                    &mut stats,
+                    &mut centroids,
+                    8,
+                    core::CV_32S,
+                )
+                .unwrap();
+
+                // Label 0 is the background
+                let mut components = Vec::new();
+                for label in 1..count {
+                    components.push(Type::List(
+                        [
+                            imgproc::CC_STAT_LEFT,
+                            imgproc::CC_STAT_TOP,
+                            imgproc::CC_STAT_WIDTH,
+                            imgproc::CC_STAT_HEIGHT,
+                            imgproc::CC_STAT_AREA,
+                        ]
+                        .iter()
+                        .map(|&stat| Type::Number(*stats.at_2d::<i32>(label, stat).unwrap() as f64))
+                        .collect(),
+                    ));
+                }
+                self.stack.push(Type::List(components))
+            }
+
+            // Get per-channel sum or mean of image pixels
+            "sum-image" | "mean-image" => {
+                let img = self.pop_stack().get_image();
+                let result = if command == "sum-image" {
+                    core::sum_elems(&img).unwrap()
+                } else {
+                    core::mean(&img, &core::no_array()).unwrap()
+                };
+                self.stack.push(Type::List(
+                    result
+                        .iter()
+                        .take(img.channels() as usize)
+                        .map(|x| Type::Number(*x))
+                        .collect(),
+                ))
+            }
+
+            // Save list of images as animated GIF
+            // (save-gif takes path, frames and delay; make-gif takes frames, path and delay)
+            "save-gif" | "make-gif" => {
+                let delay = self.pop_stack().get_number();
+                let (images, path) = if command == "save-gif" {
+                    let images = self.pop_stack().get_list();
+                    (images, self.pop_stack().get_string())
+                } else {
+                    let path = self.pop_stack().get_string();
+                    (self.pop_stack().get_list(), path)
+                };
+                let images: Vec<Mat> = images.iter().map(|x| x.get_image()).collect();
+                if images.is_empty() || images.iter().any(|img| img.empty()) {
+                    self.log_print(
+                        "Error! Frames must be a non-empty list of images\n".to_string(),
+                    );
+                    self.stack.push(Type::Error(command));
+                    return;
+                }
+
+                // GIF dimensions are 16-bit
+                let limit = u16::MAX as i32;
+                if images[0].cols() > limit || images[0].rows() > limit {
+                    self.log_print("Error! Frames are too large for GIF\n".to_string());
+                    self.stack.push(Type::Error(command));
+                    return;
+                }
+                if let Err(e) = write_gif(&path, &images, delay) {
+                    self.log_print(format!("Error! {e}\n"));
+                    self.stack.push(Type::Error(command));
+                }
+            }
+
+            // Draw outline of mask region on image with [b g r] color
+            "draw-mask-outline" => {
+                let thickness = self.pop_stack().get_number() as i32;
+                let color = to_color(&self.pop_stack());
+                let mask = to_gray(&self.pop_stack().get_image());
+                let mut img = to_bgr(&self.pop_stack().get_image());
+
+                let mut contours = core::Vector::<core::Vector<core::Point>>::new();
+                imgproc::find_contours(
+                    &mask,
+                    &mut contours,
+                    imgproc::RETR_EXTERNAL,
+                    imgproc::CHAIN_APPROX_SIMPLE,
+                    core::Point::default(),
+                )
+                .unwrap();
+                imgproc::draw_contours(
+                    &mut img,
+                    &contours,
+                    -1,
+                    color,
+                    thickness,
+                    imgproc::LINE_AA,
+                    &core::no_array(),
+                    i32::MAX,
+                    core::Point::default(),
+                )
+                .unwrap();
+                self.stack.push(Type::Image(img))
+            }
+
+            // Get rotated bounding box of contour as [cx cy width height angle]
+            "min-area-rect" => {
+                let contour = match to_points(&self.pop_stack().get_list()) {
+                    Some(points) if !points.is_empty() => {
+                        core::Vector::<core::Point2f>::from(points)
+                    }
+                    _ => {
+                        self.log_print("Error! Contour must be list of [x y] points\n".to_string());
+                        self.stack.push(Type::Error("min-area-rect".to_string()));
+                        return;
+                    }
+                };
+
+                let rect = imgproc::min_area_rect(&contour).unwrap();
+                self.stack.push(Type::List(vec![
+                    Type::Number(rect.center.x as f64),
+                    Type::Number(rect.center.y as f64),
+                    Type::Number(rect.size.width as f64),
+                    Type::Number(rect.size.height as f64),
+                    Type::Number(rect.angle as f64),
+                ]))
+            }
+
+            // Modify image to top-down view of the largest quadrilateral document
+            "deskew-document" => {
+                fn find_document(img: &Mat) -> Option<Vec<core::Point2f>> {
+                    let mut blurred_img = Mat::default();
+                    imgproc::gaussian_blur(
+                        &to_gray(img),
+                        &mut blurred_img,
+                        core::Size::new(5, 5),
+                        0.0,
+                        0.0,
+                        core::BORDER_DEFAULT,
+                    )
+                    .unwrap();
+                    let mut edges = Mat::default();
+                    imgproc::canny(&blurred_img, &mut edges, 75.0, 200.0, 3, false).unwrap();
+
+                    let mut contours = core::Vector::<core::Vector<core::Point>>::new();
+                    imgproc::find_contours(
+                        &edges,
+                        &mut contours,
+                        imgproc::RETR_EXTERNAL,
+                        imgproc::CHAIN_APPROX_SIMPLE,
+                        core::Point::default(),
+                    )
+                    .unwrap();
+
+                    // Try contours from the largest one
+                    let mut contours: Vec<(f64, core::Vector<core::Point>)> = contours
+                        .into_iter()
+                        .map(|contour| (imgproc::contour_area(&contour, false).unwrap(), contour))
+                        .collect();
+                    contours.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+                    for (_, contour) in contours {
+                        let epsilon = 0.02 * imgproc::arc_length(&contour, true).unwrap();
+                        let mut polygon = core::Vector::<core::Point>::new();
+                        imgproc::approx_poly_dp(&contour, &mut polygon, epsilon, true).unwrap();
+                        if polygon.len() == 4 {
+                            return Some(
+                                polygon
+                                    .iter()
+                                    .map(|point| core::Point2f::new(point.x as f32, point.y as f32))
+                                    .collect(),
+                            );
+                        }
+                    }
+                    None
+                }
+
+                fn deskew(img: &Mat, corners: Vec<core::Point2f>) -> Mat {
+                    // Order corners as top-left, top-right, bottom-right, bottom-left
+                    let by = |key: fn(&core::Point2f) -> f32, largest: bool| {
+                        let iter = corners.iter().copied();
+                        if largest {
+                            iter.max_by(|a, b| key(a).total_cmp(&key(b))).unwrap()
+                        } else {
+                            iter.min_by(|a, b| key(a).total_cmp(&key(b))).unwrap()
+                        }
+                    };
+                    let top_left = by(|p| p.x + p.y, false);
+                    let bottom_right = by(|p| p.x + p.y, true);
+                    let top_right = by(|p| p.y - p.x, false);
+                    let bottom_left = by(|p| p.y - p.x, true);
+
+                    let distance = |a: core::Point2f, b: core::Point2f| (a - b).norm() as f32;
+                    let width =
+                        distance(top_left, top_right).max(distance(bottom_left, bottom_right));
+                    let height =
+                        distance(top_left, bottom_left).max(distance(top_right, bottom_right));
+
+                    let src = core::Vector::<core::Point2f>::from(vec![
+                        top_left,
+                        top_right,
+                        bottom_right,
+                        bottom_left,
+                    ]);
+                    let dst = core::Vector::<core::Point2f>::from(vec![
+                        core::Point2f::new(0.0, 0.0),
+                        core::Point2f::new(width - 1.0, 0.0),
+                        core::Point2f::new(width - 1.0, height - 1.0),
+                        core::Point2f::new(0.0, height - 1.0),
+                    ]);
+                    let matrix =
+                        imgproc::get_perspective_transform(&src, &dst, core::DECOMP_LU).unwrap();
+
+                    let mut warped_img = Mat::default();
+                    imgproc::warp_perspective(
+                        img,
+                        &mut warped_img,
+                        &matrix,
+                        core::Size::new(width as i32, height as i32),
+                        imgproc::INTER_LINEAR,
+                        core::BORDER_CONSTANT,
+                        core::Scalar::all(0.0),
+                    )
+                    .unwrap();
+                    warped_img
+                }
+
+                let img = &self.pop_stack().get_image();
+                match find_document(img) {
+                    Some(corners) => self.stack.push(Type::Image(deskew(img, corners))),
+                    None => {
+                        self.log_print("Error! No document quadrilateral is found\n".to_string());
+                        self.stack.push(Type::Error("no-document".to_string()));
+                    }
+                }
+            }
+
+            // Get convex hull of contour as list of [x y] points
+            "convex-hull" => {
+                let contour = match to_points(&self.pop_stack().get_list()) {
+                    Some(points) if !points.is_empty() => {
+                        core::Vector::<core::Point2f>::from(points)
+                    }
+                    _ => {
+                        self.log_print("Error! Contour must be list of [x y] points\n".to_string());
+                        self.stack.push(Type::Error("convex-hull".to_string()));
+                        return;
+                    }
+                };
+
+                let mut hull = core::Vector::<core::Point2f>::new();
+                imgproc::convex_hull(&contour, &mut hull, false, true).unwrap();
+                self.stack.push(from_points(&hull))
+            }
+
+            // Commands of OpenCV video processing
+
+            // Open video file, or camera by its index
+            "open-video" => {
+                let capture = match self.pop_stack() {
+                    Type::Number(index) => {
+                        videoio::VideoCapture::new(index as i32, videoio::CAP_ANY)
+                    }
+                    source => {
+                        videoio::VideoCapture::from_file(&source.get_string(), videoio::CAP_ANY)
+                    }
+                };
+                match capture {
+                    Ok(capture) if capture.is_opened().unwrap_or(false) => self
+                        .stack
+                        .push(Type::Video(Capture(Arc::new(Mutex::new(capture))))),
+                    _ => {
+                        self.log_print("Error! failed to open the video\n".to_string());
+                        self.stack.push(Type::Error("open-video".to_string()));
+                    }
+                }
+            }
+
+            // Read next frame of video
+            "read-frame" => {
+                let video = match self.pop_stack() {
+                    Type::Video(video) => video,
+                    _ => {
+                        self.stack.push(Type::Error("not-video".to_string()));
+                        return;
+                    }
+                };
+                let mut frame = Mat::default();
+                let read = video.0.lock().unwrap().read(&mut frame).unwrap_or(false);
+                if read && !frame.empty() {
+                    self.stack.push(Type::Image(frame));
+                } else {
+                    self.stack.push(Type::Error("end-of-stream".to_string()));
+                }
+            }
+
+            // Jump to the frame of video, so that next read-frame returns it
+            "seek-frame" => {
+                let index = self.pop_stack().get_number();
+                let video = match self.pop_stack() {
+                    Type::Video(video) => video,
+                    _ => {
+                        self.stack.push(Type::Error("not-video".to_string()));
+                        return;
+                    }
+                };
+                let mut capture = video.0.lock().unwrap();
+
+                // Live cameras have no frame count and can't seek
+                let count = capture.get(videoio::CAP_PROP_FRAME_COUNT).unwrap_or(0.0);
+                let index = index.clamp(0.0, (count - 1.0).max(0.0)).floor();
+                if count <= 0.0
+                    || !capture
+                        .set(videoio::CAP_PROP_POS_FRAMES, index)
+                        .unwrap_or(false)
+                {
+                    self.log_print("Error! The video doesn't support seeking\n".to_string());
+                    self.stack.push(Type::Error("seek".to_string()));
+                }
+            }
+
+            // Save every Nth frame of video file as numbered images
+            "extract-frames" => {
+                let step = self.pop_stack().get_number();
+                let directory = self.pop_stack().get_string();
+                let path = self.pop_stack().get_string();
+                if step.is_nan() || step < 1.0 {
+                    self.log_print("Error! Step must be 1 or more\n".to_string());
+                    self.stack.push(Type::Error("extract-frames".to_string()));
+
+                    return;
+                }
+                let mut capture = match videoio::VideoCapture::from_file(&path, videoio::CAP_ANY) {
+                    Ok(capture) if capture.is_opened().unwrap_or(false) => capture,
+                    _ => {
+                        self.log_print("Error! failed to open the video\n".to_string());
+                        self.stack.push(Type::Error("open-video".to_string()));
+                        return;
+                    }
+                };
+
+                let (mut index, mut written) = (0, 0);
+                let mut frame = Mat::default();
+                while capture.read(&mut frame).unwrap_or(false) && !frame.empty() {
+                    if index % step as usize == 0 {
+                        let name = Path::new(&directory).join(format!("frame_{written:05}.png"));
+                        let saved = imgcodecs::imwrite(
+                            &name.to_string_lossy(),
+                            &frame,
+                            &core::Vector::new(),
+                        )
+                        .unwrap_or(false);
+                        if !saved {
+                            self.log_print(format!(
+                                "Error! failed to write {}\n",
+                                name.to_string_lossy()
+                            ));
+                            self.stack.push(Type::Error("extract-frames".to_string()));
+                            return;
+                        }
+                        written += 1;
+                    }
+                    index += 1;
+                }
+                self.stack.push(Type::Number(written as f64));
+            }
+
+            // Get video properties as [fps frame-count width height]
+            "video-info" => {
+                let video = match self.pop_stack() {
+                    Type::Video(video) => video,
+                    _ => {
+                        self.stack.push(Type::Error("not-video".to_string()));
+                        return;
+                    }
+                };
+                let capture = video.0.lock().unwrap();
+                let get = |property| capture.get(property).unwrap_or(0.0).max(0.0);
+                let info = vec![
+                    Type::Number(get(videoio::CAP_PROP_FPS)),
+                    // Live cameras have no frame count, so it becomes 0
+                    Type::Number(get(videoio::CAP_PROP_FRAME_COUNT)),
+                    Type::Number(get(videoio::CAP_PROP_FRAME_WIDTH)),
+                    Type::Number(get(videoio::CAP_PROP_FRAME_HEIGHT)),
+                ];
+                self.stack.push(Type::List(info));
+            }
+
+            // Create object tracker by algorithm name (KCF or CSRT)
+            #[cfg(feature = "tracker")]
+            "create-tracker" => {
+                let algorithm = self.pop_stack().get_string().to_uppercase();
+                let tracker: opencv::Result<core::Ptr<video::Tracker>> = match algorithm.as_str() {
+                    "KCF" => tracking::TrackerKCF::create_def().map(|tracker| tracker.into()),
+                    "CSRT" => tracking::TrackerCSRT::create_def().map(|tracker| tracker.into()),
+                    _ => {
+                        self.log_print(format!("Error! Unknown tracker algorithm {algorithm}\n"));
+                        self.stack.push(Type::Error("create-tracker".to_string()));
+                        return;
+                    }
+                };
+                match tracker {
+                    Ok(tracker) => self
+                        .stack
+                        .push(Type::Tracker(Tracking(Arc::new(Mutex::new(tracker))))),
+                    Err(_) => {
+                        self.log_print("Error! failed to create the tracker\n".to_string());
+                        self.stack.push(Type::Error("create-tracker".to_string()));
+                    }
+                }
+            }
+
+            // Start tracking object inside [x y w h] box of frame
+            #[cfg(feature = "tracker")]
+            "init-tracker" => {
+                let rect: Vec<i32> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number() as i32)
+                    .collect();
+                let frame = self.pop_stack().get_image();
+                let tracker = match self.pop_stack() {
+                    Type::Tracker(tracker) => tracker,
+                    _ => {
+                        self.stack.push(Type::Error("not-tracker".to_string()));
+                        return;
+                    }
+                };
+                if rect.len() != 4 || rect[2] <= 0 || rect[3] <= 0 || frame.empty() {
+                    self.log_print("Error! Box must be [x y w h] on non-empty frame\n".to_string());
+                    self.stack.push(Type::Error("init-tracker".to_string()));
+                    return;
+                }
+                let rect = core::Rect::new(rect[0], rect[1], rect[2], rect[3]);
+                if tracker.0.lock().unwrap().init(&frame, rect).is_err() {
+                    self.log_print("Error! failed to initialize the tracker\n".to_string());
+                    self.stack.push(Type::Error("init-tracker".to_string()));
+                }
+            }
+
+            // Follow tracked object into next frame and get its [x y w h] box
+            #[cfg(feature = "tracker")]
+            "update-tracker" => {
+                let frame = self.pop_stack().get_image();
+                let tracker = match self.pop_stack() {
+                    Type::Tracker(tracker) => tracker,
+                    _ => {
+                        self.stack.push(Type::Error("not-tracker".to_string()));
+                        return;
+                    }
+                };
+                let mut rect = core::Rect::default();
+                let found = !frame.empty()
+                    && tracker
+                        .0
+                        .lock()
+                        .unwrap()
+                        .update(&frame, &mut rect)
+                        .unwrap_or(false);
+                if found {
+                    self.stack.push(Type::List(
+                        [rect.x, rect.y, rect.width, rect.height]
+                            .iter()
+                            .map(|x| Type::Number(*x as f64))
+                            .collect(),
+                    ));
+                } else {
+                    self.stack.push(Type::Error("lost-track".to_string()));
+                }
+            }
+
+            // Intersection or union box of two [x y w h] boxes
+            // (disjoint boxes intersect as [0 0 0 0])
+            "rect-intersect" | "rect-union" => {
+                let b: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                let a: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                if a.len() != 4 || b.len() != 4 {
+                    self.log_print("Error! Box must be [x y w h]\n".to_string());
+                    self.stack.push(Type::Error("rect".to_string()));
+                    return;
+                }
+
+                let (left, top, right, bottom) = if command == "rect-intersect" {
+                    (
+                        a[0].max(b[0]),
+                        a[1].max(b[1]),
+                        (a[0] + a[2]).min(b[0] + b[2]),
+                        (a[1] + a[3]).min(b[1] + b[3]),
+                    )
+                } else {
+                    (
+                        a[0].min(b[0]),
+                        a[1].min(b[1]),
+                        (a[0] + a[2]).max(b[0] + b[2]),
+                        (a[1] + a[3]).max(b[1] + b[3]),
+                    )
+                };
+                let rect = if right > left && bottom > top {
+                    [left, top, right - left, bottom - top]
+                } else {
+                    [0.0; 4]
+                };
+                self.stack
+                    .push(Type::List(rect.iter().map(|x| Type::Number(*x)).collect()))
+            }
+
+            // Judge is [x y] point inside [x y w h] box (boundary counts as inside)
+            // (the point is popped first, then the box)
+            "point-in-rect" => {
+                let point: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                let rect: Vec<f64> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number())
+                    .collect();
+                if rect.len() != 4 || point.len() != 2 {
+                    self.log_print(
+                        "Error! Point must be [x y] and box must be [x y w h]\n".to_string(),
+                    );
+                    self.stack.push(Type::Error("point-in-rect".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Bool(
+                    (rect[0]..=rect[0] + rect[2]).contains(&point[0])
+                        && (rect[1]..=rect[1] + rect[3]).contains(&point[1]),
+                ))
+            }
+
+            // Judge is [x y] point inside contour (boundary counts as inside)
+            // (the point is popped first, then the contour)
+            "point-in-contour" => {
+                let point = to_points(&[self.pop_stack()]);
+                let contour = to_points(&self.pop_stack().get_list());
+                let (contour, point) = match (contour, point) {
+                    (Some(contour), Some(point)) if !contour.is_empty() => (contour, point[0]),
+                    _ => {
+                        self.log_print(
+                            "Error! Point and contour must be made of [x y]\n".to_string(),
+                        );
+                        self.stack.push(Type::Error("point-in-contour".to_string()));
+                        return;
+                    }
+                };
+
+                // Result is positive inside, zero on the edge, and negative outside
+                let distance = imgproc::point_polygon_test(
+                    &core::Vector::<core::Point2f>::from(contour),
+                    point,
+                    false,
+                )
+                .unwrap();
+                self.stack.push(Type::Bool(distance >= 0.0))
+            }
+
+            // Modify image by 2x3 affine matrix into [width height] output
+            "warp-affine" => {
+                let size = self.pop_stack().get_list();
+                let matrix: Vec<Vec<f64>> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|row| row.get_list().iter().map(|x| x.get_number()).collect())
+                    .collect();
+                let img = &self.pop_stack().get_image();
+                if matrix.len() != 2 || matrix.iter().any(|row| row.len() != 3) || size.len() != 2 {
+                    self.log_print("Error! Affine matrix must be 2x3\n".to_string());
+                    self.stack.push(Type::Error("warp-matrix".to_string()));
+                    return;
+                }
+
+                let matrix = Mat::from_slice_2d(&matrix).unwrap();
+                let size =
+                    core::Size::new(size[0].get_number() as i32, size[1].get_number() as i32);
+                self.stack.push(Type::Image(warp_image(img, &matrix, size)))
+            }
+
+            // Rotate [x y] point around [cx cy] center by degrees
+            // (same direction as get_rotation_matrix_2d, counter-clockwise on image)
+            "rotate-point" => {
+                let angle = self.pop_stack().get_number().to_radians();
+                let center = to_points(&[self.pop_stack()]);
+                let point = to_points(&[self.pop_stack()]);
+                let (center, point) = match (center, point) {
+                    (Some(center), Some(point)) => (center[0], point[0]),
+                    _ => {
+                        self.log_print("Error! Point and center must be [x y]\n".to_string());
+                        self.stack.push(Type::Error("rotate-point".to_string()));
+                        return;
+                    }
+                };
+
+                let (a, b) = (angle.cos(), angle.sin());
+                let (dx, dy) = ((point.x - center.x) as f64, (point.y - center.y) as f64);
+                self.stack.push(Type::List(vec![
+                    Type::Number(center.x as f64 + a * dx + b * dy),
+                    Type::Number(center.y as f64 - b * dx + a * dy),
+                ]))
+            }
+
+            // Modify image to reduce its colors into K clusters by k-means
+            "color-quantize" => {
+                fn color_quantize(img: &Mat, k: i32) -> Mat {
+                    let color_img = to_bgr(img);
+
+                    // One sample row of 3 values per pixel
+                    let samples = color_img
+                        .reshape(1, color_img.rows() * color_img.cols())
+                        .unwrap();
+                    let mut samples_f = Mat::default();
+                    samples
+                        .convert_to(&mut samples_f, core::CV_32F, 1.0, 0.0)
+                        .unwrap();
+
+                    let (mut labels, mut centers) = (Mat::default(), Mat::default());
+                    let criteria = core::TermCriteria::new(
+                        core::TermCriteria_COUNT + core::TermCriteria_EPS,
+                        10,
+                        1.0,
+                    )
+                    .unwrap();
+                    core::kmeans(
+                        &samples_f,
+                        k,
+                        &mut labels,
+                        criteria,
+                        3,
+                        core::KMEANS_PP_CENTERS,
+                        &mut centers,
+                    )
+                    .unwrap();
+
+                    // Replace each pixel with the center of its cluster
+                    let mut quantized_img = Mat::new_rows_cols_with_default(
+                        color_img.rows(),
+                        color_img.cols(),
+                        core::CV_8UC3,
+                        core::Scalar::all(0.0),
+                    )
+                    .unwrap();
+                    let pixels = quantized_img.data_typed_mut::<core::Vec3b>().unwrap();
+                    for (index, pixel) in pixels.iter_mut().enumerate() {
+                        let label = *labels.at::<i32>(index as i32).unwrap();
+                        for channel in 0..3 {
+                            let value = *centers.at_2d::<f32>(label, channel).unwrap();
+                            pixel[channel as usize] = value.round().clamp(0.0, 255.0) as u8;
+                        }
+                    }
+                    quantized_img
+                }
+
+                let k = self.pop_stack().get_number();
+                let img = &self.pop_stack().get_image();
+
+                // k-means needs at least one pixel per cluster
+                let pixels = img.rows() as f64 * img.cols() as f64;
+                if k.is_nan() || k < 2.0 || img.empty() || k > pixels {
+                    self.log_print(
+                        "Error! Cluster count must be from 2 to the number of pixels\n".to_string(),
+                    );
+                    self.stack.push(Type::Error("color-quantize".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Image(color_quantize(img, k as i32)))
+            }
+
+            // Extract foreground inside [x y w h] box by GrabCut (background is black)
+            "grabcut" => {
+                fn grabcut(img: &Mat, rect: core::Rect) -> Mat {
+                    let color_img = to_bgr(img);
+                    let (mut mask, mut bgd_model, mut fgd_model) =
+                        (Mat::default(), Mat::default(), Mat::default());
+                    imgproc::grab_cut(
+                        &color_img,
+                        &mut mask,
+                        rect,
+                        &mut bgd_model,
+                        &mut fgd_model,
+                        5,
+                        imgproc::GC_INIT_WITH_RECT,
+                    )
+                    .unwrap();
+
+                    // Both sure and probable foreground have the lowest bit set
+                    let mut fg_bit = Mat::default();
+                    core::bitwise_and(
+                        &mask,
+                        &core::Scalar::all(1.0),
+                        &mut fg_bit,
+                        &core::no_array(),
+                    )
+                    .unwrap();
+                    let mut fg_mask = Mat::default();
+                    core::compare(&fg_bit, &core::Scalar::all(0.0), &mut fg_mask, core::CMP_GT)
+                        .unwrap();
+
+                    let mut foreground = Mat::new_rows_cols_with_default(
+                        color_img.rows(),
+                        color_img.cols(),
+                        core::CV_8UC3,
+                        core::Scalar::all(0.0),
+                    )
+                    .unwrap();
+                    color_img.copy_to_masked(&mut foreground, &fg_mask).unwrap();
+                    foreground
+                }
+
+                let rect: Vec<i32> = self
+                    .pop_stack()
+                    .get_list()
+                    .iter()
+                    .map(|x| x.get_number() as i32)
+                    .collect();
+                let img = self.pop_stack().get_image();
+                if rect.len() != 4 {
+                    self.log_print("Error! Box must be [x y w h]\n".to_string());
+                    self.stack.push(Type::Error("grabcut".to_string()));
+                    return;
+                }
+
+                // Clip the box into the image
+                let rect = core::Rect::new(rect[0], rect[1], rect[2], rect[3])
+                    & core::Rect::new(0, 0, img.cols(), img.rows());
+                if img.empty() || rect.width <= 0 || rect.height <= 0 {
+                    self.log_print("Error! Image and box must not be empty\n".to_string());
+                    self.stack.push(Type::Error("grabcut".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Image(grabcut(&img, rect)))
+            }
+
+            // Segment image by watershed and colorize each region (boundaries are white)
+            // Markers is a grayscale image of the same size as the source:
+            // each connected non-zero blob is one seed (the background needs its own seed),
+            // and zero pixels are the unknown area to be flooded.
+            "watershed" => {
+                fn watershed(img: &Mat, seeds: &Mat) -> Mat {
+                    let mut markers = Mat::default();
+                    imgproc::connected_components(seeds, &mut markers, 8, core::CV_32S).unwrap();
+                    imgproc::watershed(&to_bgr(img), &mut markers).unwrap();
+
+                    let mut segmented = Mat::new_rows_cols_with_default(
+                        markers.rows(),
+                        markers.cols(),
+                        core::CV_8UC3,
+                        core::Scalar::all(0.0),
+                    )
+                    .unwrap();
+                    let labels = markers.data_typed::<i32>().unwrap();
+                    let pixels = segmented.data_typed_mut::<core::Vec3b>().unwrap();
+                    for (pixel, &label) in pixels.iter_mut().zip(labels) {
+                        *pixel = if label == -1 {
+                            core::Vec3b::from([255, 255, 255])
+                        } else {
+                            // Spread the label numbers over distinct colors
+                            let label = label as u32;
+                            core::Vec3b::from([
+                                (label * 67 % 200 + 40) as u8,
+                                (label * 131 % 200 + 40) as u8,
+                                (label * 199 % 200 + 40) as u8,
+                            ])
+                        };
+                    }
+                    segmented
+                }
+
+                let seeds = to_gray(&self.pop_stack().get_image());
+                let img = self.pop_stack().get_image();
+                if img.empty() || img.size().unwrap() != seeds.size().unwrap() {
+                    self.log_print("Error! Markers must be the same size as image\n".to_string());
+                    self.stack.push(Type::Error("watershed".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Image(watershed(&img, &seeds)))
+            }
+
+            // Visualize dense optical flow between two frames as motion magnitude color map
+            "optical-flow" => {
+                fn optical_flow(prev: &Mat, next: &Mat) -> Mat {
+                    let prev_gray = to_gray(prev);
+                    let mut next_gray = to_gray(next);
+
+                    // Match the second frame to the first one
+                    if next_gray.size().unwrap() != prev_gray.size().unwrap() {
+                        let mut resized_img = Mat::default();
+                        resize(
+                            &next_gray,
+                            &mut resized_img,
+                            prev_gray.size().unwrap(),
+                            0.0,
+                            0.0,
+                            imgproc::INTER_LINEAR,
+                        )
+                        .unwrap();
+                        next_gray = resized_img;
+                    }
+
+                    let mut flow = Mat::default();
+                    video::calc_optical_flow_farneback(
+                        &prev_gray, &next_gray, &mut flow, 0.5, 3, 15, 3, 5, 1.2, 0,
+                    )
+                    .unwrap();
+
+                    let mut components = core::Vector::<Mat>::new();
+                    core::split(&flow, &mut components).unwrap();
+                    let (mut magnitude, mut angle) = (Mat::default(), Mat::default());
+                    core::cart_to_polar(
+                        &components.get(0).unwrap(),
+                        &components.get(1).unwrap(),
+                        &mut magnitude,
+                        &mut angle,
+                        false,
+                    )
+                    .unwrap();
+
+                    let mut magnitude_img = Mat::default();
+                    core::normalize(
+                        &magnitude,
+                        &mut magnitude_img,
+                        0.0,
+                        255.0,
+                        core::NORM_MINMAX,
+                        core::CV_8U,
+                        &core::no_array(),
+                    )
+                    .unwrap();
+                    let mut color_img = Mat::default();
+                    imgproc::apply_color_map(&magnitude_img, &mut color_img, imgproc::COLORMAP_JET)
+                        .unwrap();
+                    color_img
+                }
+
+                let next = self.pop_stack().get_image();
+                let prev = self.pop_stack().get_image();
+                if prev.empty() || next.empty() {
+                    self.log_print("Error! Frames must not be empty\n".to_string());
+                    self.stack.push(Type::Error("optical-flow".to_string()));
+                    return;
+                }
+                self.stack.push(Type::Image(optical_flow(&prev, &next)))
+            }
+
+            // If it is not recognized as a command, use it as a string.
+            _ => {
+                if let Some(profile) = &mut self.profile {
+                    profile.is_word = true;
+                }
+                self.stack.push(Type::String(command))
+            }
+        }
+    }
+
+    /// Run the closure, then restore the variable that it shadows
+    fn keep_variable<T>(&mut self, name: &str, f: impl FnOnce(&mut Executor) -> T) -> T {
+        let shadowed = self.memory.get(name).cloned();
+        let result = f(self);
+        match shadowed {
+            Some(value) => self.memory.insert(name.to_string(), value),
+            None => self.memory.remove(name),
+        };
+        result
+    }
+
+    /// Pop stack's top value
+    fn pop_stack(&mut self) -> Type {
+        if let Some(value) = self.stack.pop() {
+            value
+        } else {
+            self.log_print(
+                "Error! There are not enough values on the stack. returns default value\n"
+                    .to_string(),
+            );
+            Type::String("".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate code and get the string on top of the stack
+    fn evaluate_string(code: &str) -> String {
+        let mut executor = Executor::new(Mode::Script);
+        executor.evaluate_program(code.to_string());
+        match executor.pop_stack() {
+            Type::String(s) => s,
+            other => panic!("unexpected value {other:?}"),
+        }
+    }
+
+    #[test]
+    fn escaped_newline_in_string() {
+        assert_eq!(evaluate_string(r"(a\nb)"), "a\nb");
+        assert_eq!(evaluate_string(r"(a\\nb)"), r"a\nb");
+    }
+
+    #[test]
+    fn escaped_newline_in_list() {
+        let mut executor = Executor::new(Mode::Script);
+        executor.evaluate_program(r"[(a\nb)]".to_string());
+        match executor.pop_stack() {
+            Type::List(list) => match list.as_slice() {
+                [Type::String(s)] => assert_eq!(s, "a\nb"),
+                other => panic!("unexpected list {other:?}"),
+            },
+            other => panic!("unexpected value {other:?}"),
+        }
+    }
+
+    #[test]
+    fn escaped_backslash_at_top_level() {
+        assert_eq!(evaluate_string(r"x\\n"), r"x\n");
+    }
+
+    #[test]
+    fn escaped_newline_in_nested_string() {
+        assert_eq!(evaluate_string(r"((a\nb)) eval"), "a\nb");
+    }
+
+    #[test]
+    fn otsu_mask_coverage_of_synthetic_image() {
+        // White 50x40 rectangle on black 100x100 canvas covers 20% of it
+        let mut img =
+            Mat::new_rows_cols_with_default(100, 100, core::CV_8UC3, core::Scalar::all(0.0))
+                .unwrap();
+        imgproc::rectangle(
+            &mut img,
+            core::Rect::new(10, 20, 50, 40),
+            core::Scalar::all(255.0),
+            imgproc::FILLED,
+            imgproc::LINE_8,
+            0,
+        )
+        .unwrap();
+
+        let path = env::temp_dir().join("stack_otsu_coverage.png");
+        let path = path.to_string_lossy().to_string();
+        assert!(imgcodecs::imwrite(&path, &img, &core::Vector::new()).unwrap());
+
+        let mut executor = Executor::new(Mode::Script);
+        executor.evaluate_program(format!(
+            "{} open-image to-grayscale threshold-otsu-mask count-nonzero",
+            Type::String(path.clone()).display()
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        let count = executor.pop_stack().get_number();
+        assert!((count / 10000.0 - 0.2).abs() < 1e-9);
     }
 }